@@ -47,9 +47,13 @@ where
                     Request::default().set_source(ConnectionSource::Port(8224))
                 }
             },
-            (Empty, ProposalKind::Idle) => Request::default(),
+            (Empty, ProposalKind::Idle { .. }) => Request::default(),
+            (Empty, ProposalKind::Listening { .. }) => Request::default(),
             (Empty, ProposalKind::Connection { .. }) => Request::default(),
-            (Empty, ProposalKind::OnReadable(id, once)) => {
+            (Empty, ProposalKind::AlreadyConnected { .. }) => Request::default(),
+            (Empty, ProposalKind::ConnectFailed { .. }) => Request::default(),
+            (Empty, ProposalKind::ResolveFailed { .. }) => Request::default(),
+            (Empty, ProposalKind::OnReadable(id, _, once)) => {
                 if !INITIATOR {
                     let mut buf = [0; 13];
                     if let IoResult::Done { length, .. } = once.read(&mut buf) {
@@ -63,13 +67,12 @@ where
                         Request::default()
                     }
                 } else {
-                    // just to be explicit in real code, can omit this `drop`
-                    // drop the `once` object, will close the reading half of the connection
-                    drop(once);
+                    // close the reading half of the connection
+                    once.close();
                     Request::default()
                 }
             },
-            (Empty, ProposalKind::OnWritable(id, once)) => {
+            (Empty, ProposalKind::OnWritable(id, _, once)) => {
                 if INITIATOR {
                     let msg = "hello, world!";
                     if let IoResult::Done { .. } = once.write(msg.as_bytes()) {
@@ -82,10 +85,17 @@ where
                         Request::default().add_connect(ADDRESS)
                     }
                 } else {
-                    drop(once);
+                    once.close();
                     Request::default()
                 }
             },
+            (Empty, ProposalKind::WriteDrained(_)) => Request::default(),
+            (Empty, ProposalKind::WriteRejected { .. }) => Request::default(),
+            (Empty, ProposalKind::Rejected { .. }) => Request::default(),
+            (Empty, ProposalKind::Disconnected { .. }) => Request::default(),
+            (Empty, ProposalKind::Ready { .. }) => Request::default(),
+            (Empty, ProposalKind::OnPriority(..)) => Request::default(),
+            (Empty, ProposalKind::Timer(_)) => Request::default(),
             (Empty, ProposalKind::Custom(_)) => Request::default(),
             (Done, _) => Request::default(),
         }
@@ -100,21 +110,21 @@ fn main() {
     let r_thread = thread::spawn(move || {
         let mut responder = TimeTracker::new(iter::repeat(()), ExampleState::<false>::Empty);
         let mut proposer = Proposer::new(1, 8);
-        while !responder.as_mut().can_terminate() {
-            proposer
-                .run(&mut responder, Duration::from_secs(1))
-                .unwrap();
-        }
+        proposer
+            .run_until(&mut responder, Duration::from_secs(1), |s| {
+                s.can_terminate()
+            })
+            .unwrap();
     });
     thread::sleep(Duration::from_millis(100));
 
     let mut initiator = TimeTracker::new(iter::repeat(()), ExampleState::<true>::Empty);
     let mut proposer = Proposer::new(0, 8);
-    while !initiator.as_mut().can_terminate() {
-        proposer
-            .run(&mut initiator, Duration::from_secs(1))
-            .unwrap();
-    }
+    proposer
+        .run_until(&mut initiator, Duration::from_secs(1), |s| {
+            s.can_terminate()
+        })
+        .unwrap();
 
     r_thread.join().unwrap();
 }
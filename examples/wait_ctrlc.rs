@@ -40,26 +40,38 @@ where
 
         match proposal.kind {
             ProposalKind::Wake => Request::default().set_source(ConnectionSource::Port(8224)),
-            ProposalKind::Idle => {
+            ProposalKind::Idle { .. } => {
                 if self.received_terminate {
-                    if let Some((&addr, _)) = self.connections.iter().next() {
-                        log::info!("will disconnect: {}", addr);
-                        self.connections.remove(&addr);
+                    if let Some((&id, _)) = self.connections.iter().next() {
+                        log::info!("will disconnect: {}", id);
+                        self.connections.remove(&id);
+                        return Request::default().disconnect_id(id);
                     }
                 }
                 Request::default()
             },
+            ProposalKind::Listening { .. } => Request::default(),
             ProposalKind::Connection { .. } => Request::default(),
-            ProposalKind::OnReadable(id, once) => {
+            ProposalKind::AlreadyConnected { .. } => Request::default(),
+            ProposalKind::ConnectFailed { .. } => Request::default(),
+            ProposalKind::ResolveFailed { .. } => Request::default(),
+            ProposalKind::OnReadable(id, _, once) => {
                 let (r, _) = self.connections.entry(id).or_default();
                 *r = Some(once);
                 Request::default()
             },
-            ProposalKind::OnWritable(id, once) => {
+            ProposalKind::OnWritable(id, _, once) => {
                 let (_, w) = self.connections.entry(id).or_default();
                 *w = Some(once);
                 Request::default()
             },
+            ProposalKind::WriteDrained(_) => Request::default(),
+            ProposalKind::WriteRejected { .. } => Request::default(),
+            ProposalKind::Rejected { .. } => Request::default(),
+            ProposalKind::Disconnected { .. } => Request::default(),
+            ProposalKind::Ready { .. } => Request::default(),
+            ProposalKind::OnPriority(..) => Request::default(),
+            ProposalKind::Timer(_) => Request::default(),
             ProposalKind::Custom("terminate") => {
                 self.received_terminate = true;
                 Request::default()
@@ -90,10 +102,10 @@ where
 
     fn accept(&mut self, proposal: Proposal<R, W, Self::Ext, Self::Rng>) -> Request {
         match proposal.kind {
-            ProposalKind::OnWritable(_, once) => {
+            ProposalKind::OnWritable(_, _, once) => {
                 self.writer = Some(once);
             },
-            ProposalKind::OnReadable(_, once) => {
+            ProposalKind::OnReadable(_, _, once) => {
                 self.reader = Some(once);
             },
             _ => (),
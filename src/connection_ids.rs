@@ -0,0 +1,97 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+use mio::Token;
+
+use super::proposal::ConnectionId;
+
+/// The ids of the connections. By default the id is the token of the stream,
+/// it depends on the order the os reports the connections and on the reuse of the tokens.
+/// The sequential ids are given in order the state receives the connections, never reused.
+pub struct ConnectionIds {
+    poll_id: u16,
+    sequential: Option<Sequential>,
+}
+
+#[derive(Default)]
+struct Sequential {
+    next: u64,
+    ids: HashMap<Token, u64>,
+    tokens: HashMap<u64, Token>,
+}
+
+impl ConnectionIds {
+    pub fn new(poll_id: u16) -> Self {
+        ConnectionIds {
+            poll_id,
+            sequential: None,
+        }
+    }
+
+    pub fn poll_id(&self) -> u16 {
+        self.poll_id
+    }
+
+    pub fn set_sequential(&mut self, sequential: bool) {
+        match (sequential, &self.sequential) {
+            (true, None) => self.sequential = Some(Sequential::default()),
+            (false, Some(_)) => self.sequential = None,
+            _ => (),
+        }
+    }
+
+    /// The id of the stream, the next sequential id if the stream has none yet.
+    pub fn id(&mut self, token: Token) -> ConnectionId {
+        let token = match &mut self.sequential {
+            None => token.0 as u64,
+            Some(s) => match s.ids.get(&token) {
+                Some(id) => *id,
+                None => {
+                    let id = s.next;
+                    s.next += 1;
+                    s.ids.insert(token, id);
+                    s.tokens.insert(id, token);
+                    id
+                },
+            },
+        };
+        ConnectionId {
+            poll_id: self.poll_id,
+            token,
+        }
+    }
+
+    /// The id of the stream, if the stream has one already.
+    pub fn known(&self, token: Token) -> Option<ConnectionId> {
+        let token = match &self.sequential {
+            None => token.0 as u64,
+            Some(s) => *s.ids.get(&token)?,
+        };
+        Some(ConnectionId {
+            poll_id: self.poll_id,
+            token,
+        })
+    }
+
+    pub fn token(&self, id: ConnectionId) -> Option<Token> {
+        if id.poll_id != self.poll_id() {
+            return None;
+        }
+        match &self.sequential {
+            None => Some(Token(id.token as usize)),
+            Some(s) => s.tokens.get(&id.token).cloned(),
+        }
+    }
+
+    /// The tokens are given to other streams from now, forget their ids.
+    pub fn release(&mut self, tokens: &[Token]) {
+        if let Some(s) = &mut self.sequential {
+            for token in tokens {
+                if let Some(id) = s.ids.remove(token) {
+                    s.tokens.remove(&id);
+                }
+            }
+        }
+    }
+}
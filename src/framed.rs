@@ -0,0 +1,259 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::VecDeque,
+    convert::{TryFrom, TryInto},
+};
+use smallvec::SmallVec;
+
+use super::proposal::{ReadOnce, IoResult};
+
+/// The width and the byte order of the length that precedes each frame.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LengthPrefix {
+    U16BigEndian,
+    U16LittleEndian,
+    #[default]
+    U32BigEndian,
+    U32LittleEndian,
+}
+
+impl LengthPrefix {
+    /// How many bytes the prefix takes.
+    pub fn width(&self) -> usize {
+        match self {
+            LengthPrefix::U16BigEndian | LengthPrefix::U16LittleEndian => 2,
+            LengthPrefix::U32BigEndian | LengthPrefix::U32LittleEndian => 4,
+        }
+    }
+
+    /// `None` if the bytes are fewer than the width.
+    pub fn decode(&self, bytes: &[u8]) -> Option<usize> {
+        let bytes = bytes.get(..self.width())?;
+        let length = match self {
+            LengthPrefix::U16BigEndian => u16::from_be_bytes(bytes.try_into().ok()?) as usize,
+            LengthPrefix::U16LittleEndian => u16::from_le_bytes(bytes.try_into().ok()?) as usize,
+            LengthPrefix::U32BigEndian => u32::from_be_bytes(bytes.try_into().ok()?) as usize,
+            LengthPrefix::U32LittleEndian => u32::from_le_bytes(bytes.try_into().ok()?) as usize,
+        };
+        Some(length)
+    }
+
+    /// `None` if the length does not fit the width.
+    pub fn encode(&self, length: usize) -> Option<SmallVec<[u8; 4]>> {
+        let bytes = match self {
+            LengthPrefix::U16BigEndian => {
+                SmallVec::from_slice(&u16::try_from(length).ok()?.to_be_bytes())
+            },
+            LengthPrefix::U16LittleEndian => {
+                SmallVec::from_slice(&u16::try_from(length).ok()?.to_le_bytes())
+            },
+            LengthPrefix::U32BigEndian => {
+                SmallVec::from_slice(&u32::try_from(length).ok()?.to_be_bytes())
+            },
+            LengthPrefix::U32LittleEndian => {
+                SmallVec::from_slice(&u32::try_from(length).ok()?.to_le_bytes())
+            },
+        };
+        Some(bytes)
+    }
+}
+
+/// Accumulates the bytes of the connection and splits them into length prefixed frames.
+/// The frame can span several `OnReadable` proposals, so keep it along with the connection.
+#[derive(Debug)]
+pub struct Framed {
+    prefix: LengthPrefix,
+    max_length: Option<usize>,
+    chunk: usize,
+    buffer: Vec<u8>,
+    frames: VecDeque<Vec<u8>>,
+    closed: bool,
+}
+
+impl Default for Framed {
+    fn default() -> Self {
+        Framed::new(LengthPrefix::default())
+    }
+}
+
+impl Framed {
+    pub fn new(prefix: LengthPrefix) -> Self {
+        Framed {
+            prefix,
+            max_length: None,
+            chunk: 0x1000,
+            buffer: Vec::new(),
+            frames: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    /// The frame longer than this closes the decoder, protects from memory exhaustion.
+    pub fn max_length(self, max: usize) -> Self {
+        let mut s = self;
+        s.max_length = Some(max);
+        s
+    }
+
+    /// How many bytes to read at once.
+    pub fn chunk(self, chunk: usize) -> Self {
+        let mut s = self;
+        s.chunk = chunk.max(1);
+        s
+    }
+
+    /// Read once and return the first complete frame, if any.
+    /// The read asks to be polled again, so the proposer keeps proposing
+    /// `OnReadable` until the os has no more data.
+    pub fn poll_frame<R>(&mut self, once: R) -> Option<Vec<u8>>
+    where
+        R: ReadOnce,
+    {
+        if self.closed {
+            once.close();
+            return self.next_frame();
+        }
+        let old = self.buffer.len();
+        self.buffer.resize(old + self.chunk, 0);
+        let result = once.read_and_keep(&mut self.buffer[old..]);
+        let length = match result {
+            IoResult::Done { length, will_close } => {
+                self.closed |= will_close;
+                length
+            },
+            IoResult::WouldBlock => 0,
+            IoResult::Closed | IoResult::Eof | IoResult::Error(_) => {
+                self.closed = true;
+                0
+            },
+        };
+        self.buffer.truncate(old + length);
+        self.split();
+        self.next_frame()
+    }
+
+    /// The frame already read, but not yet returned.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+
+    /// The connection is closed, or the frame is too long.
+    /// The frames already read are still available.
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    /// How many bytes of the incomplete frame are buffered.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn split(&mut self) {
+        let width = self.prefix.width();
+        let mut pos = 0;
+        while let Some(length) = self.prefix.decode(&self.buffer[pos..]) {
+            if self.max_length.is_some_and(|max| length > max) {
+                log::warn!("frame is too long: {}", length);
+                self.closed = true;
+                self.buffer.clear();
+                return;
+            }
+            let end = pos + width + length;
+            if self.buffer.len() < end {
+                break;
+            }
+            self.frames
+                .push_back(self.buffer[(pos + width)..end].to_vec());
+            pos = end;
+        }
+        self.buffer.drain(..pos);
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use crate::testing::MemStream;
+    use super::{Framed, LengthPrefix};
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut frame = LengthPrefix::default().encode(data.len()).unwrap().to_vec();
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn split_prefix() {
+        let stream = MemStream::new();
+        stream.set_max_read(Some(1));
+        stream.feed(&frame(b"abc"));
+        let mut framed = Framed::default();
+        for _ in 0..2 {
+            assert_eq!(framed.poll_frame(stream.read_once()), None);
+        }
+        // half of the prefix is buffered
+        assert_eq!(framed.buffered(), 2);
+        for _ in 0..2 {
+            assert_eq!(framed.poll_frame(stream.read_once()), None);
+        }
+        assert_eq!(framed.buffered(), 4);
+    }
+
+    #[test]
+    fn split_body() {
+        let stream = MemStream::new();
+        stream.set_max_read(Some(1));
+        stream.feed(&frame(b"abc"));
+        let mut framed = Framed::default();
+        for _ in 0..6 {
+            assert_eq!(framed.poll_frame(stream.read_once()), None);
+        }
+        assert_eq!(framed.poll_frame(stream.read_once()), Some(b"abc".to_vec()));
+        assert_eq!(framed.buffered(), 0);
+        assert!(!framed.closed());
+    }
+
+    #[test]
+    fn two_frames_in_one_read() {
+        let stream = MemStream::new();
+        stream.feed(&[frame(b"abc"), frame(b"de")].concat());
+        let mut framed = Framed::default();
+        assert_eq!(framed.poll_frame(stream.read_once()), Some(b"abc".to_vec()));
+        assert_eq!(framed.next_frame(), Some(b"de".to_vec()));
+        assert_eq!(framed.next_frame(), None);
+    }
+
+    #[test]
+    fn frame_too_long() {
+        let stream = MemStream::new();
+        stream.set_max_read(Some(1));
+        stream.feed(&[frame(b"ab"), frame(b"abc")].concat());
+        let mut framed = Framed::default().max_length(2);
+        let mut frames = Vec::new();
+        while !framed.closed() {
+            frames.extend(framed.poll_frame(stream.read_once()));
+        }
+        // the frame within the limit is still available
+        assert_eq!(frames, [b"ab".to_vec()]);
+        assert_eq!(framed.buffered(), 0);
+        assert_eq!(framed.poll_frame(stream.read_once()), None);
+    }
+
+    #[test]
+    fn eof_in_the_middle() {
+        let stream = MemStream::new();
+        stream.set_max_read(Some(1));
+        stream.feed(&frame(b"abc")[..5]);
+        stream.close_read();
+        let mut framed = Framed::default();
+        for _ in 0..5 {
+            assert_eq!(framed.poll_frame(stream.read_once()), None);
+        }
+        // the incomplete frame is never returned
+        assert!(framed.closed());
+        assert_eq!(framed.buffered(), 5);
+        assert_eq!(framed.poll_frame(stream.read_once()), None);
+    }
+}
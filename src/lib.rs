@@ -7,21 +7,60 @@ mod state;
 pub use self::state::State;
 
 mod request;
-pub use self::request::{Request, ConnectionSource};
+pub use self::request::{Request, ConnectionSource, Backoff, SocketOption};
 
 mod proposal;
-pub use self::proposal::{Proposal, ProposalKind, ConnectionId, ReadOnce, WriteOnce, IoResult};
+pub use self::proposal::{
+    Proposal, ProposalKind, ProposalTag, ProposalSummary, ConnectionId, ConnectionOrigin,
+    ConnectionStats, ConnectionInterest, DisconnectReason, DisconnectOrigin, RejectReason,
+    ReadOnce, WriteOnce, IoResult,
+};
 
 mod proposer;
-pub use self::proposer::Proposer;
+pub use self::proposer::{Proposer, Fairness, IdlePolicy, Trigger, DuplicateConnect, WriteOverflow};
+
+mod proposer_builder;
+pub use self::proposer_builder::ProposerBuilder;
 
 mod proposer_error;
 pub use self::proposer_error::ProposerError;
 
+mod proposer_metrics;
+pub use self::proposer_metrics::ProposerMetrics;
+
+mod framed;
+pub use self::framed::{Framed, LengthPrefix};
+
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+pub use self::tls::{Tls, TlsReadOnce, TlsWriteOnce};
+
 mod managed_stream;
 mod marked_stream;
+mod shared;
 
 mod time;
-pub use self::time::TimeTracker;
+pub use self::time::{TimeTracker, Clock, SystemClock, ManualClock};
+
+#[cfg(feature = "seeded")]
+mod seeded_rngs;
+#[cfg(feature = "seeded")]
+pub use self::seeded_rngs::SeededRngs;
 
 mod stream_registry;
+mod connection_ids;
+mod persistent;
+mod resolver;
+mod socks5;
+mod token_bucket;
+mod timer_wheel;
+
+mod record;
+pub use self::record::{
+    Record, RecordedIo, ProposalRecorder, Recording, RecordingReadOnce, RecordingWriteOnce,
+    ReplayReadOnce, ReplayWriteOnce, replay,
+};
+
+#[cfg(feature = "testing")]
+pub mod testing;
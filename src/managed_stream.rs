@@ -3,89 +3,379 @@
 
 use std::{
     io::{self, Read, Write},
-    rc::{Rc, Weak},
-    cell::{RefCell, RefMut},
-    net::Shutdown,
+    net::{Shutdown, SocketAddr},
+    collections::VecDeque,
+    time::Duration,
 };
 use mio::{Token, Interest, net::TcpStream};
 use super::{
-    marked_stream::MarkedStream,
-    proposal::{ReadOnce, WriteOnce, IoResult},
+    marked_stream::{MarkedStream, StreamOptions, Dirty, Totals},
+    shared::{Shared, WeakShared, Guard},
+    proposal::{ReadOnce, WriteOnce, IoResult, ConnectionStats, DisconnectReason},
 };
 
 pub struct ManagedStream {
-    inner: Rc<RefCell<MarkedStream>>,
+    inner: Shared<MarkedStream>,
+    addr: SocketAddr,
     token: Token,
+    incoming: bool,
+    // the stream is registered with its current interests,
+    // the next event for its token is expected
+    armed: bool,
+    registered: Interest,
 }
 
 impl ManagedStream {
-    pub fn new(stream: TcpStream, token: Token) -> Self {
+    pub fn new(
+        stream: TcpStream,
+        addr: SocketAddr,
+        token: Token,
+        incoming: bool,
+        options: StreamOptions,
+        dirty: Dirty,
+        totals: Totals,
+    ) -> Self {
         ManagedStream {
-            inner: Rc::new(RefCell::new(MarkedStream {
+            inner: Shared::new(MarkedStream {
                 stream,
                 reader: false,
                 reader_discarded: false,
                 reader_used: false,
+                reader_pending: false,
+                read_paused: false,
                 writer: false,
                 writer_discarded: false,
                 writer_used: false,
-            })),
+                write_paused: false,
+                queued: VecDeque::new(),
+                queue_limit: None,
+                frame_ends: VecDeque::new(),
+                shutdown_queued: false,
+                peer_closed: false,
+                error: None,
+                closing: None,
+                options,
+                stats: ConnectionStats::default(),
+                totals,
+                token,
+                dirty,
+            }),
+            addr,
             token,
+            incoming,
+            armed: true,
+            registered: if incoming {
+                Interest::READABLE
+            } else {
+                Interest::WRITABLE
+            },
         }
     }
 
     pub fn write_once(&self) -> Option<TcpWriteOnce> {
-        let mut s = self.inner.borrow_mut();
-        if !s.writer && !s.writer_discarded {
+        let mut s = self.inner.lock();
+        if !s.writer && !s.writer_discarded && !s.write_paused {
             s.writer = true;
-            Some(TcpWriteOnce(Rc::downgrade(&self.inner)))
+            Some(TcpWriteOnce(self.inner.downgrade()))
         } else {
             None
         }
     }
 
     pub fn read_once(&self) -> Option<TcpReadOnce> {
-        let mut s = self.inner.borrow_mut();
-        if !s.reader && !s.reader_discarded {
+        let mut s = self.inner.lock();
+        if !s.reader && !s.reader_discarded && !s.read_paused {
             s.reader = true;
-            Some(TcpReadOnce(Rc::downgrade(&self.inner)))
+            s.reader_pending = false;
+            Some(TcpReadOnce {
+                inner: self.inner.downgrade(),
+                urgent: false,
+            })
         } else {
             None
         }
     }
 
+    /// The reader of the urgent byte, if the peer sent it and it is not read yet.
+    /// It does not affect the regular reader.
+    #[cfg(unix)]
+    pub fn urgent_once(&self) -> Option<TcpReadOnce> {
+        use std::mem::MaybeUninit;
+
+        let s = self.inner.lock();
+        if s.reader_discarded {
+            return None;
+        }
+        let mut byte = [MaybeUninit::uninit()];
+        let flags = libc::MSG_OOB | libc::MSG_PEEK;
+        match socket2::SockRef::from(&s.stream).recv_with_flags(&mut byte, flags) {
+            Ok(1) => Some(TcpReadOnce {
+                inner: self.inner.downgrade(),
+                urgent: true,
+            }),
+            // `InvalidInput` if there is no urgent byte
+            _ => None,
+        }
+    }
+
     pub fn discard(self) -> io::Result<()> {
-        let mut s = self.inner.borrow_mut();
+        let mut s = self.inner.lock();
+        s.reader_discarded = true;
+        s.writer_discarded = true;
+        match s.as_mut().shutdown(Shutdown::Both) {
+            // the connection failed, or the peer is gone already
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            result => result,
+        }
+    }
+
+    /// The proposer writes the data when the connection is writable,
+    /// the state should not write directly until the queue is drained.
+    /// The frame is queued as a whole, so it is never interleaved with other data.
+    /// Returns false if the queue would exceed the limit, or the stream takes no more data,
+    /// nothing is queued then.
+    pub fn queue(&self, data: &[u8], frame: bool) -> bool {
+        let mut s = self.borrow_mut();
+        // nothing goes after the goodbye of the closing stream
+        if s.writer_discarded || s.closing.is_some() {
+            return false;
+        }
+        if s.queue_limit
+            .is_some_and(|limit| s.queued.len() + data.len() > limit)
+        {
+            return false;
+        }
+        s.queued.extend(data);
+        if frame {
+            let end = s.queued.len();
+            s.frame_ends.push_back(end);
+        }
+        s.mark_dirty();
+        true
+    }
+
+    /// The stream is closing, or its write half is shut down, the queue takes nothing.
+    pub fn queue_closed(&self) -> bool {
+        let s = self.inner.lock();
+        s.writer_discarded || s.closing.is_some()
+    }
+
+    pub fn set_queue_limit(&self, limit: Option<usize>) {
+        self.borrow_mut().queue_limit = limit;
+    }
+
+    pub fn queued_len(&self) -> usize {
+        self.inner.lock().queued.len()
+    }
+
+    /// How many frames are not completely written yet.
+    pub fn queued_frames(&self) -> usize {
+        self.inner.lock().frame_ends.len()
+    }
+
+    /// Write the queued data, `None` if nothing is queued,
+    /// otherwise whether the queue is drained.
+    pub fn flush(&self) -> Option<bool> {
+        let mut s = self.borrow_mut();
+        let s = &mut *s;
+        if s.queued.is_empty() {
+            return None;
+        }
+        while !s.queued.is_empty() {
+            let (data, _) = s.queued.as_slices();
+            match s.stream.write(data) {
+                Ok(0) => {
+                    s.peer_closed = true;
+                    s.writer_discarded = true;
+                    s.clear_queue();
+                    return Some(false);
+                },
+                Ok(length) => {
+                    s.dequeue(length);
+                    s.count_written(length);
+                },
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Some(false),
+                Err(error) => {
+                    if error.kind() != io::ErrorKind::NotConnected {
+                        log::error!("io error: {}", error);
+                        s.error = Some(error.kind());
+                    }
+                    // the queued data cannot be delivered
+                    s.writer_discarded = true;
+                    s.clear_queue();
+                    s.shutdown(Shutdown::Write);
+                    return Some(false);
+                },
+            }
+        }
+        if s.shutdown_queued {
+            s.writer_discarded = true;
+            s.shutdown(Shutdown::Write);
+        }
+        Some(true)
+    }
+
+    /// Queue the data as the last one, the connection is closed once it is written.
+    /// The state is offered neither the reader nor the writer meanwhile.
+    /// Returns false if the queue would exceed the limit, nothing is queued then.
+    pub fn close_after_flush(&self, data: &[u8], reason: DisconnectReason) -> bool {
+        // the first goodbye is queued already
+        if self.inner.lock().closing.is_some() {
+            return true;
+        }
+        if !self.queue(data, false) {
+            return false;
+        }
+        {
+            let mut s = self.borrow_mut();
+            s.closing = Some(reason);
+            s.write_paused = true;
+        }
+        self.shutdown(Shutdown::Read);
+        self.shutdown(Shutdown::Write);
+        true
+    }
+
+    /// Shut down the half of the connection, the other half keeps working.
+    /// The write half is shut down after the queued data is written.
+    pub fn shutdown(&self, how: Shutdown) {
+        let mut s = self.borrow_mut();
+        match how {
+            Shutdown::Write if !s.queued.is_empty() => s.shutdown_queued = true,
+            Shutdown::Write => {
+                s.writer_discarded = true;
+                s.shutdown(Shutdown::Write);
+            },
+            Shutdown::Read => {
+                s.reader_discarded = true;
+                s.shutdown(Shutdown::Read);
+            },
+            Shutdown::Both => {
+                s.reader_discarded = true;
+                s.writer_discarded = true;
+                s.clear_queue();
+                s.shutdown(Shutdown::Both);
+            },
+        }
+        s.mark_dirty();
+    }
+
+    /// The reader is not offered until resumed, the interest in reading is dropped,
+    /// so the os stops accepting the data once its buffer is full.
+    pub fn set_read_paused(&self, paused: bool) {
+        let mut s = self.borrow_mut();
+        s.read_paused = paused;
+        s.mark_dirty();
+    }
+
+    pub fn read_paused(&self) -> bool {
+        self.inner.lock().read_paused
+    }
+
+    /// The writer is not offered until resumed, but the queued data is written.
+    pub fn set_write_paused(&self, paused: bool) {
+        let mut s = self.borrow_mut();
+        s.write_paused = paused;
+        s.mark_dirty();
+    }
+
+    pub fn write_paused(&self) -> bool {
+        self.inner.lock().write_paused
+    }
+
+    /// Set zero linger, so closing the socket sends RST rather than FIN.
+    /// The data not yet sent, or queued, is discarded. The socket is closed on drop.
+    pub fn reset(self) -> io::Result<()> {
+        let mut s = self.inner.lock();
         s.reader_discarded = true;
         s.writer_discarded = true;
-        s.as_mut().shutdown(Shutdown::Both)
+        s.clear_queue();
+        socket2::SockRef::from(&s.stream).set_linger(Some(Duration::ZERO))
+    }
+
+    pub fn set_options(&self, options: StreamOptions) {
+        self.borrow_mut().options = options;
+    }
+
+    pub fn borrow_mut(&self) -> Guard<'_, MarkedStream> {
+        self.inner.lock()
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.inner.lock().stats
     }
 
-    pub fn borrow_mut(&self) -> RefMut<MarkedStream> {
-        self.inner.as_ref().borrow_mut()
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
     }
 
     pub fn token(&self) -> Token {
         self.token
     }
 
+    /// The connection is accepted, rather than initiated by the proposer.
+    pub fn incoming(&self) -> bool {
+        self.incoming
+    }
+
+    /// The stream is registered with the interests.
+    pub fn arm(&mut self, interests: Interest) {
+        self.armed = true;
+        self.registered = interests;
+    }
+
+    /// The interests the proposer is waiting for, `None` if it is waiting for nothing.
+    pub fn armed_interests(&self) -> Option<Interest> {
+        Some(self.registered).filter(|_| self.armed)
+    }
+
+    /// Returns whether the stream was armed.
+    pub fn disarm(&mut self) -> bool {
+        std::mem::replace(&mut self.armed, false)
+    }
+
+    pub fn mark_dirty(&self) {
+        self.inner.lock().mark_dirty();
+    }
+
     pub fn closed(&self) -> bool {
-        let s = self.inner.borrow();
+        let s = self.inner.lock();
         s.reader_discarded && s.writer_discarded
     }
 
+    /// The state asked to read again, and the reader is not in use.
+    pub fn read_pending(&self) -> bool {
+        let s = self.inner.lock();
+        s.reader_pending && !s.reader && !s.reader_discarded && !s.read_paused
+    }
+
     pub fn set_read_closed(&self) {
-        self.borrow_mut().reader_discarded = true;
+        let mut s = self.borrow_mut();
+        s.reader_discarded = true;
+        s.peer_closed = true;
     }
 
     pub fn set_write_closed(&self) {
-        self.borrow_mut().writer_discarded = true;
+        let mut s = self.borrow_mut();
+        s.writer_discarded = true;
+        s.peer_closed = true;
+    }
+
+    /// Why the stream is closed, if the proposer did not close it by the request.
+    pub fn close_reason(&self) -> DisconnectReason {
+        let s = self.inner.lock();
+        match (s.error, s.closing) {
+            (Some(kind), _) => DisconnectReason::Error(kind),
+            (None, Some(reason)) => reason,
+            (None, None) if s.peer_closed => DisconnectReason::PeerClosed,
+            (None, None) => DisconnectReason::Requested,
+        }
     }
 
     pub fn interests(&self) -> Option<Interest> {
-        let s = self.inner.borrow();
-        let read = !s.reader && !s.reader_discarded;
-        let write = !s.writer && !s.writer_discarded;
+        let s = self.inner.lock();
+        let read = !s.reader && !s.reader_discarded && !s.read_paused;
+        let write = ((!s.writer && !s.write_paused) || !s.queued.is_empty()) && !s.writer_discarded;
         match (read, write) {
             (true, true) => Some(Interest::READABLE | Interest::WRITABLE),
             (true, false) => Some(Interest::READABLE),
@@ -95,26 +385,36 @@ impl ManagedStream {
     }
 }
 
-pub struct TcpWriteOnce(Weak<RefCell<MarkedStream>>);
+pub struct TcpWriteOnce(WeakShared<MarkedStream>);
 
-impl WriteOnce for TcpWriteOnce {
-    fn write(self, data: &[u8]) -> IoResult {
+impl TcpWriteOnce {
+    // `requested` is the length of the data the stream writes
+    fn write_with<F>(&self, requested: usize, write: F) -> IoResult
+    where
+        F: FnOnce(&mut TcpStream) -> io::Result<usize>,
+    {
         if let Some(s) = self.0.upgrade() {
-            let mut s = s.borrow_mut();
+            let mut s = s.lock();
             let will_close = s.writer_discarded;
             s.writer_used = true;
-            match s.as_mut().write(data) {
-                Ok(length) => IoResult::Done { length, will_close },
-                Err(error) => {
-                    log::error!("io error: {}", error);
-                    match error.kind() {
-                        io::ErrorKind::NotConnected => IoResult::Closed,
-                        io::ErrorKind::WouldBlock => IoResult::Done {
-                            length: 0,
-                            will_close,
-                        },
-                        _ => IoResult::Closed,
-                    }
+            match write(s.as_mut()) {
+                Ok(0) if requested != 0 => {
+                    s.peer_closed = true;
+                    s.writer_discarded = true;
+                    IoResult::Eof
+                },
+                Ok(length) => {
+                    s.count_written(length);
+                    IoResult::Done { length, will_close }
+                },
+                Err(error) => match error.kind() {
+                    io::ErrorKind::WouldBlock => IoResult::WouldBlock,
+                    io::ErrorKind::NotConnected => IoResult::Closed,
+                    kind => {
+                        log::error!("io error: {}", error);
+                        s.error = Some(kind);
+                        IoResult::Error(kind)
+                    },
                 },
             }
         } else {
@@ -123,45 +423,139 @@ impl WriteOnce for TcpWriteOnce {
     }
 }
 
+impl WriteOnce for TcpWriteOnce {
+    fn write(self, data: &[u8]) -> IoResult {
+        self.write_with(data.len(), |stream| stream.write(data))
+    }
+
+    #[cfg(feature = "bytes")]
+    fn write_buf<B>(self, buf: &mut B) -> IoResult
+    where
+        B: bytes::Buf,
+    {
+        let result = {
+            let mut slices = [io::IoSlice::new(&[]); 16];
+            let count = buf.chunks_vectored(&mut slices);
+            let slices = &slices[..count];
+            let requested = slices.iter().map(|slice| slice.len()).sum();
+            self.write_with(requested, |stream| stream.write_vectored(slices))
+        };
+        if let IoResult::Done { length, .. } = result {
+            buf.advance(length);
+        }
+        result
+    }
+
+    fn defer(self) {
+        if let Some(s) = self.0.upgrade() {
+            s.lock().writer_used = true;
+        }
+    }
+
+    fn close(self) {
+        if let Some(s) = self.0.upgrade() {
+            let mut s = s.lock();
+            s.writer_discarded = true;
+            s.shutdown(Shutdown::Write);
+        }
+    }
+}
+
 impl Drop for TcpWriteOnce {
     fn drop(&mut self) {
         if let Some(s) = self.0.upgrade() {
-            let mut s = s.borrow_mut();
-            s.writer_discarded = !s.writer_used;
+            let mut s = s.lock();
+            let unused = !s.writer_used;
             s.writer_used = false;
             s.writer = false;
-            if let Err(error) = s.as_mut().shutdown(Shutdown::Write) {
-                // it is expected the socket is not connected,
-                // don't report this case
-                if !matches!(error.kind(), io::ErrorKind::NotConnected) {
-                    log::error!("io error: {}", error);
-                }
+            s.mark_dirty();
+            if unused && s.options.close_on_drop && !s.writer_discarded {
+                s.writer_discarded = true;
+                s.shutdown(Shutdown::Write);
             }
         }
     }
 }
 
 #[must_use = "discard it if don't need"]
-pub struct TcpReadOnce(Weak<RefCell<MarkedStream>>);
+pub struct TcpReadOnce {
+    inner: WeakShared<MarkedStream>,
+    // reads the out of band byte, the regular reader is not affected
+    urgent: bool,
+}
 
-impl ReadOnce for TcpReadOnce {
-    fn read(self, buf: &mut [u8]) -> IoResult {
-        if let Some(s) = self.0.upgrade() {
-            let mut s = s.borrow_mut();
+impl TcpReadOnce {
+    #[cfg(unix)]
+    fn read_urgent(&self, buf: &mut [u8]) -> IoResult {
+        use std::os::unix::io::AsRawFd;
+        use nix::sys::socket::{self, MsgFlags};
+
+        let s = match self.inner.upgrade() {
+            Some(s) => s,
+            None => return IoResult::Closed,
+        };
+        let mut s = s.lock();
+        // tcp has at most one urgent byte,
+        // unlike socket2, nix reads it into the initialized buffer
+        let length = buf.len().min(1);
+        let fd = s.stream.as_raw_fd();
+        match socket::recv(fd, &mut buf[..length], MsgFlags::MSG_OOB).map_err(io::Error::from) {
+            Ok(length) => {
+                s.count_read(length);
+                IoResult::Done {
+                    length,
+                    will_close: false,
+                }
+            },
+            Err(error) => match error.kind() {
+                // the urgent byte is read already, or not arrived yet
+                io::ErrorKind::WouldBlock | io::ErrorKind::InvalidInput => IoResult::WouldBlock,
+                io::ErrorKind::NotConnected => IoResult::Closed,
+                kind => IoResult::Error(kind),
+            },
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn read_urgent(&self, _buf: &mut [u8]) -> IoResult {
+        IoResult::WouldBlock
+    }
+
+    // `requested` is the length of the buffer the stream reads into
+    fn read_with<F>(&self, requested: usize, read: F) -> IoResult
+    where
+        F: FnOnce(&mut TcpStream) -> io::Result<usize>,
+    {
+        if let Some(s) = self.inner.upgrade() {
+            let mut s = s.lock();
             let will_close = s.reader_discarded;
             s.reader_used = true;
-            match s.as_mut().read(buf) {
-                Ok(length) => IoResult::Done { length, will_close },
-                Err(error) => {
-                    log::error!("io error: {}", error);
-                    match error.kind() {
-                        io::ErrorKind::NotConnected => IoResult::Closed,
-                        io::ErrorKind::WouldBlock => IoResult::Done {
-                            length: 0,
-                            will_close,
-                        },
-                        _ => IoResult::Closed,
+            match read(s.as_mut()) {
+                Ok(0) if requested != 0 => {
+                    // the peer will send nothing more
+                    s.peer_closed = true;
+                    s.reader_discarded = true;
+                    s.reader_pending = false;
+                    IoResult::Eof
+                },
+                Ok(length) => {
+                    s.count_read(length);
+                    if s.options.level_triggered && length != 0 && length == requested {
+                        s.reader_pending = true;
                     }
+                    IoResult::Done { length, will_close }
+                },
+                Err(error) => match error.kind() {
+                    io::ErrorKind::WouldBlock => {
+                        s.reader_pending = false;
+                        IoResult::WouldBlock
+                    },
+                    io::ErrorKind::NotConnected => IoResult::Closed,
+                    kind => {
+                        log::error!("io error: {}", error);
+                        s.error = Some(kind);
+                        IoResult::Error(kind)
+                    },
                 },
             }
         } else {
@@ -170,19 +564,50 @@ impl ReadOnce for TcpReadOnce {
     }
 }
 
+impl ReadOnce for TcpReadOnce {
+    fn read(self, buf: &mut [u8]) -> IoResult {
+        if self.urgent {
+            return self.read_urgent(buf);
+        }
+        self.read_with(buf.len(), |stream| stream.read(buf))
+    }
+
+    fn read_and_keep(self, buf: &mut [u8]) -> IoResult {
+        if let Some(s) = self.inner.upgrade().filter(|_| !self.urgent) {
+            s.lock().reader_pending = true;
+        }
+        self.read(buf)
+    }
+
+    fn defer(self) {
+        if let Some(s) = self.inner.upgrade().filter(|_| !self.urgent) {
+            s.lock().reader_used = true;
+        }
+    }
+
+    fn close(self) {
+        if let Some(s) = self.inner.upgrade() {
+            let mut s = s.lock();
+            s.reader_discarded = true;
+            s.shutdown(Shutdown::Read);
+        }
+    }
+}
+
 impl Drop for TcpReadOnce {
     fn drop(&mut self) {
-        if let Some(s) = self.0.upgrade() {
-            let mut s = s.borrow_mut();
-            s.reader_discarded = !s.reader_used;
+        if self.urgent {
+            return;
+        }
+        if let Some(s) = self.inner.upgrade() {
+            let mut s = s.lock();
+            let unused = !s.reader_used;
             s.reader_used = false;
             s.reader = false;
-            if let Err(error) = s.as_mut().shutdown(Shutdown::Read) {
-                // it is expected the socket is not connected,
-                // don't report this case
-                if !matches!(error.kind(), io::ErrorKind::NotConnected) {
-                    log::error!("io error: {}", error);
-                }
+            s.mark_dirty();
+            if unused && s.options.close_on_drop && !s.reader_discarded {
+                s.reader_discarded = true;
+                s.shutdown(Shutdown::Read);
             }
         }
     }
@@ -1,16 +1,103 @@
 // Copyright 2021 Vladislav Melnik
 // SPDX-License-Identifier: MIT
 
-use mio::net::TcpStream;
+use std::{io, net::Shutdown, collections::VecDeque};
+use mio::{Token, net::TcpStream};
+
+use super::{
+    proposal::{ConnectionStats, DisconnectReason},
+    shared::Shared,
+};
+
+/// The bytes transferred over all connections of the proposer.
+pub type Totals = Shared<ConnectionStats>;
+
+/// The tokens of streams whose interests might have changed since the last registration.
+/// A token might be marked several times, the buffer is reused, so marking does not allocate.
+pub type Dirty = Shared<Vec<Token>>;
 
 pub struct MarkedStream {
     pub stream: TcpStream,
     pub reader: bool,
     pub reader_discarded: bool,
     pub reader_used: bool,
+    pub reader_pending: bool,
+    // the state paused reading, the reader is not offered
+    pub read_paused: bool,
     pub writer: bool,
     pub writer_discarded: bool,
     pub writer_used: bool,
+    // the state paused writing, the writer is not offered, the queue is still written
+    pub write_paused: bool,
+    pub queued: VecDeque<u8>,
+    // the queue must not grow beyond this
+    pub queue_limit: Option<usize>,
+    // the offsets in the queue where the frames end
+    pub frame_ends: VecDeque<usize>,
+    // shut down the write half once the queue is drained
+    pub shutdown_queued: bool,
+    // the peer closed the connection, or the os reported the half is closed
+    pub peer_closed: bool,
+    // the last io error
+    pub error: Option<io::ErrorKind>,
+    // the proposer closes the connection once the queue is drained
+    pub closing: Option<DisconnectReason>,
+    pub options: StreamOptions,
+    pub stats: ConnectionStats,
+    pub totals: Totals,
+    pub token: Token,
+    pub dirty: Dirty,
+}
+
+/// The behavior of the stream, the proposer sets it for every stream.
+#[derive(Default, Clone, Copy)]
+pub struct StreamOptions {
+    /// Dropping unused `ReadOnce` or `WriteOnce` closes the half of the connection.
+    pub close_on_drop: bool,
+    /// The read which fills the whole buffer asks to be polled again.
+    pub level_triggered: bool,
+}
+
+impl MarkedStream {
+    pub fn mark_dirty(&self) {
+        self.dirty.lock().push(self.token);
+    }
+
+    pub fn clear_queue(&mut self) {
+        self.queued.clear();
+        self.frame_ends.clear();
+    }
+
+    /// The bytes in front of the queue are written.
+    pub fn dequeue(&mut self, length: usize) {
+        self.queued.drain(..length);
+        while self.frame_ends.front().is_some_and(|end| *end <= length) {
+            self.frame_ends.pop_front();
+        }
+        for end in &mut self.frame_ends {
+            *end -= length;
+        }
+    }
+
+    pub fn count_read(&mut self, length: usize) {
+        self.stats.bytes_read += length as u64;
+        self.totals.lock().bytes_read += length as u64;
+    }
+
+    pub fn count_written(&mut self, length: usize) {
+        self.stats.bytes_written += length as u64;
+        self.totals.lock().bytes_written += length as u64;
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) {
+        if let Err(error) = self.stream.shutdown(how) {
+            // it is expected the socket is not connected,
+            // don't report this case
+            if !matches!(error.kind(), io::ErrorKind::NotConnected) {
+                log::error!("io error: {}", error);
+            }
+        }
+    }
 }
 
 impl AsMut<TcpStream> for MarkedStream {
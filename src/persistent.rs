@@ -0,0 +1,143 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use mio::Token;
+use rand::Rng;
+
+use super::{request::Backoff, timer_wheel::TimerWheel};
+
+struct Entry {
+    backoff: Backoff,
+    attempt: u32,
+    token: Option<Token>,
+    established: bool,
+}
+
+/// The addresses the proposer keeps connected to.
+pub struct PersistentConnects {
+    entries: BTreeMap<SocketAddr, Entry>,
+    connecting: BTreeMap<Token, SocketAddr>,
+    // when to attempt to connect, the time does not depend on the number of addresses
+    due: TimerWheel<SocketAddr>,
+}
+
+impl PersistentConnects {
+    pub fn new() -> Self {
+        PersistentConnects {
+            entries: BTreeMap::default(),
+            connecting: BTreeMap::default(),
+            due: TimerWheel::new(),
+        }
+    }
+
+    pub fn add(&mut self, addr: SocketAddr, backoff: Backoff, now: Instant) {
+        if self.entries.contains_key(&addr) {
+            return;
+        }
+        self.entries.insert(
+            addr,
+            Entry {
+                backoff,
+                attempt: 0,
+                token: None,
+                established: false,
+            },
+        );
+        self.due.schedule(addr, now);
+    }
+
+    /// Returns the connection in progress, it should be dropped.
+    pub fn cancel(&mut self, addr: SocketAddr) -> Option<Token> {
+        let entry = self.entries.remove(&addr)?;
+        self.due.cancel(&addr);
+        match entry.token {
+            Some(token) if !entry.established => self.connecting.remove(&token).map(|_| token),
+            _ => None,
+        }
+    }
+
+    /// Schedule the reconnect to the addresses whose connection is gone.
+    pub fn check_alive<F>(&mut self, now: Instant, alive: F)
+    where
+        F: Fn(Token) -> bool,
+    {
+        for (addr, entry) in &mut self.entries {
+            if let Some(token) = entry.token {
+                if !alive(token) {
+                    self.connecting.remove(&token);
+                    entry.token = None;
+                    if entry.established {
+                        entry.established = false;
+                        entry.attempt = 0;
+                        self.due.schedule(*addr, now);
+                    } else if !self.due.contains(addr) {
+                        self.due.schedule(*addr, now);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn take_due(&mut self, now: Instant) -> Vec<SocketAddr> {
+        self.due.expire(now)
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.due.next_deadline()
+    }
+
+    pub fn connecting(&self, token: Token) -> Option<SocketAddr> {
+        self.connecting.get(&token).cloned()
+    }
+
+    pub fn attach(&mut self, addr: SocketAddr, token: Token, established: bool) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            entry.token = Some(token);
+            entry.established = established;
+            if established {
+                entry.attempt = 0;
+            } else {
+                self.connecting.insert(token, addr);
+            }
+        }
+    }
+
+    pub fn established(&mut self, addr: SocketAddr) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            if let Some(token) = entry.token {
+                self.connecting.remove(&token);
+            }
+            entry.established = true;
+            entry.attempt = 0;
+        }
+    }
+
+    /// Schedule the next attempt, returns the delay.
+    pub fn failed<R>(&mut self, addr: SocketAddr, now: Instant, rng: &mut R) -> Duration
+    where
+        R: Rng,
+    {
+        let entry = match self.entries.get_mut(&addr) {
+            Some(entry) => entry,
+            None => return Duration::ZERO,
+        };
+        if let Some(token) = entry.token.take() {
+            self.connecting.remove(&token);
+        }
+        entry.established = false;
+        let delay = entry.backoff.delay(entry.attempt);
+        let delay = if entry.backoff.jitter {
+            rng.gen_range((delay / 2)..=delay)
+        } else {
+            delay
+        };
+        entry.attempt = entry.attempt.saturating_add(1);
+        self.due.schedule(addr, now + delay);
+        delay
+    }
+}
@@ -1,21 +1,172 @@
 // Copyright 2021 Vladislav Melnik
 // SPDX-License-Identifier: MIT
 
-use std::{time::Duration, net::SocketAddr, fmt};
+use std::{time::Duration, net::SocketAddr, fmt, io};
+
+// how much of the spare capacity `read_buf` fills and reads at once
+#[cfg(feature = "bytes")]
+const READ_BUF_CHUNK: usize = 0x4000;
 
 pub trait ReadOnce {
     fn read(self, buf: &mut [u8]) -> IoResult;
+
+    /// Read and ask to be polled again, even if the os will not report the readiness.
+    /// Useful when the state knows there is more data, e.g. a frame spans several reads.
+    fn read_and_keep(self, buf: &mut [u8]) -> IoResult
+    where
+        Self: Sized,
+    {
+        self.read(buf)
+    }
+
+    /// Read into the spare capacity of the buffer and advance its length,
+    /// reserve the capacity before. The result is the same as of `read`.
+    /// At most 16 KiB are read at once, only they are filled with zeros first
+    /// and truncated to the bytes read, so the buffer never holds uninitialized bytes.
+    #[cfg(feature = "bytes")]
+    fn read_buf(self, buf: &mut bytes::BytesMut) -> IoResult
+    where
+        Self: Sized,
+    {
+        let start = buf.len();
+        let chunk = (buf.capacity() - start).min(READ_BUF_CHUNK);
+        buf.resize(start + chunk, 0);
+        let result = self.read(&mut buf[start..]);
+        let length = match result {
+            IoResult::Done { length, .. } => length,
+            _ => 0,
+        };
+        buf.truncate(start + length);
+        result
+    }
+
+    /// Don't read now, the proposer will send `OnReadable` again.
+    /// Dropping the object is the same, unless the proposer is set to close on drop.
+    fn defer(self)
+    where
+        Self: Sized,
+    {
+    }
+
+    /// Close the reading half of the connection.
+    fn close(self)
+    where
+        Self: Sized,
+    {
+    }
 }
 
 pub trait WriteOnce {
     fn write(self, data: &[u8]) -> IoResult;
+
+    /// Write from the buffer and advance it by the bytes written.
+    /// The result is the same as of `write`. The tcp writer writes the chained
+    /// chunks at once, the default writes only the first chunk.
+    #[cfg(feature = "bytes")]
+    fn write_buf<B>(self, buf: &mut B) -> IoResult
+    where
+        Self: Sized,
+        B: bytes::Buf,
+    {
+        let result = self.write(buf.chunk());
+        if let IoResult::Done { length, .. } = result {
+            buf.advance(length);
+        }
+        result
+    }
+
+    /// Don't write now, the proposer will send `OnWritable` again.
+    /// Dropping the object is the same, unless the proposer is set to close on drop.
+    fn defer(self)
+    where
+        Self: Sized,
+    {
+    }
+
+    /// Close the writing half of the connection.
+    fn close(self)
+    where
+        Self: Sized,
+    {
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[must_use = "need to know how many bytes was actually read or written"]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum IoResult {
     Closed,
-    Done { length: usize, will_close: bool },
+    Done {
+        length: usize,
+        will_close: bool,
+    },
+    /// Nothing can be read or written right now, try again later.
+    WouldBlock,
+    /// The remote peer closed the connection cleanly, no more data.
+    Eof,
+    /// The io failed, the connection is likely broken.
+    Error(#[cfg_attr(feature = "serde", serde(with = "error_kind"))] io::ErrorKind),
+}
+
+#[cfg(feature = "serde")]
+mod error_kind {
+    use std::io::ErrorKind;
+    use serde::{Serializer, Deserializer, Deserialize};
+
+    macro_rules! kinds {
+        ($($kind:ident),*) => {
+            fn to_str(kind: ErrorKind) -> &'static str {
+                match kind {
+                    $(ErrorKind::$kind => stringify!($kind),)*
+                    _ => "Other",
+                }
+            }
+
+            fn from_str(s: &str) -> ErrorKind {
+                match s {
+                    $(stringify!($kind) => ErrorKind::$kind,)*
+                    _ => ErrorKind::Other,
+                }
+            }
+        };
+    }
+
+    kinds!(
+        NotFound,
+        PermissionDenied,
+        ConnectionRefused,
+        ConnectionReset,
+        ConnectionAborted,
+        NotConnected,
+        AddrInUse,
+        AddrNotAvailable,
+        BrokenPipe,
+        AlreadyExists,
+        WouldBlock,
+        InvalidInput,
+        InvalidData,
+        TimedOut,
+        WriteZero,
+        Interrupted,
+        Unsupported,
+        UnexpectedEof,
+        OutOfMemory
+    );
+
+    pub fn serialize<S>(kind: &ErrorKind, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(to_str(*kind))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<ErrorKind, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(from_str(&s))
+    }
 }
 
 /// The proposal is the input to the state machine.
@@ -23,35 +174,450 @@ pub enum IoResult {
 pub struct Proposal<R, W, Ext, Rng> {
     pub rng: Rng,
     pub elapsed: Duration,
+    /// The sum of all `elapsed`, the monotonic time since the first proposal.
+    pub since_start: Duration,
     pub kind: ProposalKind<R, W, Ext>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConnectionId {
     pub poll_id: u16,
-    pub token: u16,
+    /// Wide enough to hold any `mio::Token`, so ids of live connections never collide.
+    /// The token of a closed connection may be given to a new one.
+    pub token: u64,
+}
+
+/// How many bytes were transferred over the connection.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// Why the connection is gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DisconnectReason {
+    /// The state requested to disconnect or to reset, or closed both halves itself.
+    Requested,
+    /// The state blacklisted the peer.
+    Blacklisted,
+    /// The peer closed the connection.
+    PeerClosed,
+    /// The queue of the data to write exceeded the limit, the peer does not read fast enough.
+    WriteBufferFull,
+    /// The io failed.
+    Error(#[cfg_attr(feature = "serde", serde(with = "error_kind"))] io::ErrorKind),
 }
 
+/// Why the incoming connection is dropped right after it is accepted,
+/// or the outgoing one is not attempted.
+/// The accept rate limit and the paused listener have no reason here, the proposer
+/// does not accept at all then, the connections wait in the backlog of the listener
+/// and nothing is known about their peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RejectReason {
+    /// The ip of the peer is blacklisted.
+    Blacklisted,
+    /// The proposer has the maximal number of connections.
+    MaxConnections,
+    /// The peer's ip has the maximal number of connections.
+    PerIpLimit,
+}
+
+/// Where the connection comes from.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConnectionOrigin {
+    /// Accepted from the listener bound to the address.
+    Listener(SocketAddr),
+    /// The state requested to connect.
+    #[default]
+    Outgoing,
+    /// The stream is adopted with `Proposer::adopt_stream`.
+    Adopted,
+}
+
+/// Which side ended the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DisconnectOrigin {
+    /// The state ended it, by the request or by closing the connection itself.
+    Local,
+    /// The peer ended it, or the network failed, the state did not ask for it.
+    Remote,
+}
+
+impl DisconnectReason {
+    /// Useful for the reconnect policy, e.g. reconnect only if the peer dropped the connection.
+    pub fn origin(&self) -> DisconnectOrigin {
+        match self {
+            DisconnectReason::Requested
+            | DisconnectReason::Blacklisted
+            | DisconnectReason::WriteBufferFull => DisconnectOrigin::Local,
+            DisconnectReason::PeerClosed | DisconnectReason::Error(_) => DisconnectOrigin::Remote,
+        }
+    }
+}
+
+/// The readiness the proposer is waiting for on the connection.
+/// Both false if the state holds the once objects, or the connection is closed.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionInterest {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ProposalKind<R, W, Ext> {
     /// Wake the state machine, useful if the state machine
     /// needs to request something before it receives any event
     Wake,
-    /// Nothing happened during a time quant
-    Idle,
+    /// Nothing happened during a time quant,
+    /// `timed_out` is false if the proposer was woken up spuriously before the timeout
+    Idle { timed_out: bool },
+    /// The proposer is listening incoming connections at the address,
+    /// the actual port is known here if the state requested the port 0
+    Listening { addr: SocketAddr },
     /// New connection
     Connection {
         addr: SocketAddr,
+        /// Accepted from the listener, `false` if the state requested to connect,
+        /// or the stream is adopted.
         incoming: bool,
+        /// Same as `incoming`, but tells the listener, e.g. the tls and the plaintext ports.
+        // absent in the logs recorded before it was introduced, `incoming` tells then
+        #[cfg_attr(feature = "serde", serde(default))]
+        origin: ConnectionOrigin,
         id: ConnectionId,
     },
+    /// The state requested to connect to the peer it is already connected to,
+    /// no new connection is made.
+    AlreadyConnected { addr: SocketAddr, id: ConnectionId },
+    /// The persistent connection to the peer failed, the proposer will retry.
+    /// Or the connection through the proxy, or the reconnect with the same id, failed,
+    /// it is not retried and `retry_in` is zero.
+    ConnectFailed {
+        addr: SocketAddr,
+        retry_in: Duration,
+    },
+    /// The host name requested to connect to could not be resolved,
+    /// or no address accepted the connection.
+    ResolveFailed { host: String, port: u16 },
     /// The remote peer can provide data.
-    OnReadable(ConnectionId, R),
+    OnReadable(ConnectionId, SocketAddr, R),
     /// The remote peer can accept data.
-    OnWritable(ConnectionId, W),
+    OnWritable(ConnectionId, SocketAddr, W),
+    /// The remote peer sent the urgent (out of band) byte, the reader reads it
+    /// rather than the regular data. Only on unix, if the proposer is set to check it.
+    OnPriority(ConnectionId, SocketAddr, R),
+    /// The last byte queued with `Request::queue_write` is written.
+    WriteDrained(ConnectionId),
+    /// The data was not queued, the queue would exceed the limit set for the connection,
+    /// the connection is closing or shut down for writing,
+    /// or the frame is too long for its length prefix.
+    /// Nothing of it is written, the data queued before is not affected.
+    WriteRejected { id: ConnectionId, length: usize },
+    /// The incoming connection is dropped, the state never receives it,
+    /// or the connection to the blacklisted peer is not attempted.
+    /// The proposer reports it only if it is set to.
+    Rejected {
+        addr: SocketAddr,
+        reason: RejectReason,
+    },
+    /// The connection is gone, the proposer reports it only if it is set to.
+    Disconnected {
+        id: ConnectionId,
+        addr: SocketAddr,
+        reason: DisconnectReason,
+        stats: ConnectionStats,
+    },
+    /// The connection is readable, writable or both, instead of the separate
+    /// `OnReadable` and `OnWritable` if the proposer is set to coalesce them.
+    Ready {
+        id: ConnectionId,
+        addr: SocketAddr,
+        read: Option<R>,
+        write: Option<W>,
+    },
+    /// The timer set with `Request::set_timer` is due, the key is the one it was set with.
+    Timer(u64),
     /// User-defined
     Custom(Ext),
 }
 
+/// The variant of the `ProposalKind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProposalTag {
+    Wake,
+    Idle,
+    Listening,
+    Connection,
+    AlreadyConnected,
+    ConnectFailed,
+    ResolveFailed,
+    OnReadable,
+    OnWritable,
+    OnPriority,
+    WriteDrained,
+    WriteRejected,
+    Rejected,
+    Disconnected,
+    Ready,
+    Timer,
+    Custom,
+}
+
+impl ProposalTag {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProposalTag::Wake => "wake",
+            ProposalTag::Idle => "idle",
+            ProposalTag::Listening => "listening",
+            ProposalTag::Connection => "connection",
+            ProposalTag::AlreadyConnected => "already_connected",
+            ProposalTag::ConnectFailed => "connect_failed",
+            ProposalTag::ResolveFailed => "resolve_failed",
+            ProposalTag::OnReadable => "on_readable",
+            ProposalTag::OnWritable => "on_writable",
+            ProposalTag::OnPriority => "on_priority",
+            ProposalTag::WriteDrained => "write_drained",
+            ProposalTag::WriteRejected => "write_rejected",
+            ProposalTag::Rejected => "rejected",
+            ProposalTag::Disconnected => "disconnected",
+            ProposalTag::Ready => "ready",
+            ProposalTag::Timer => "timer",
+            ProposalTag::Custom => "custom",
+        }
+    }
+}
+
+impl fmt::Display for ProposalTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The tag of the proposal and the fields common for many variants.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProposalSummary<'a, Ext> {
+    pub tag: ProposalTag,
+    pub id: Option<ConnectionId>,
+    pub addr: Option<SocketAddr>,
+    pub incoming: Option<bool>,
+    pub custom: Option<&'a Ext>,
+}
+
+impl<R, W, Ext> ProposalKind<R, W, Ext> {
+    /// Replace the io objects, keep everything else.
+    pub fn map_io<Rm, Wm, Fr, Fw>(self, fr: Fr, fw: Fw) -> ProposalKind<Rm, Wm, Ext>
+    where
+        Fr: FnOnce(R) -> Rm,
+        Fw: FnOnce(W) -> Wm,
+    {
+        match self {
+            ProposalKind::Wake => ProposalKind::Wake,
+            ProposalKind::Idle { timed_out } => ProposalKind::Idle { timed_out },
+            ProposalKind::Listening { addr } => ProposalKind::Listening { addr },
+            ProposalKind::Connection {
+                addr,
+                incoming,
+                origin,
+                id,
+            } => ProposalKind::Connection {
+                addr,
+                incoming,
+                origin,
+                id,
+            },
+            ProposalKind::AlreadyConnected { addr, id } => {
+                ProposalKind::AlreadyConnected { addr, id }
+            },
+            ProposalKind::ConnectFailed { addr, retry_in } => {
+                ProposalKind::ConnectFailed { addr, retry_in }
+            },
+            ProposalKind::ResolveFailed { host, port } => {
+                ProposalKind::ResolveFailed { host, port }
+            },
+            ProposalKind::OnReadable(id, addr, r) => ProposalKind::OnReadable(id, addr, fr(r)),
+            ProposalKind::OnPriority(id, addr, r) => ProposalKind::OnPriority(id, addr, fr(r)),
+            ProposalKind::OnWritable(id, addr, w) => ProposalKind::OnWritable(id, addr, fw(w)),
+            ProposalKind::WriteDrained(id) => ProposalKind::WriteDrained(id),
+            ProposalKind::WriteRejected { id, length } => {
+                ProposalKind::WriteRejected { id, length }
+            },
+            ProposalKind::Rejected { addr, reason } => ProposalKind::Rejected { addr, reason },
+            ProposalKind::Disconnected {
+                id,
+                addr,
+                reason,
+                stats,
+            } => ProposalKind::Disconnected {
+                id,
+                addr,
+                reason,
+                stats,
+            },
+            ProposalKind::Ready {
+                id,
+                addr,
+                read,
+                write,
+            } => ProposalKind::Ready {
+                id,
+                addr,
+                read: read.map(fr),
+                write: write.map(fw),
+            },
+            ProposalKind::Timer(key) => ProposalKind::Timer(key),
+            ProposalKind::Custom(ext) => ProposalKind::Custom(ext),
+        }
+    }
+
+    pub fn tag(&self) -> ProposalTag {
+        match self {
+            ProposalKind::Wake => ProposalTag::Wake,
+            ProposalKind::Idle { .. } => ProposalTag::Idle,
+            ProposalKind::Listening { .. } => ProposalTag::Listening,
+            ProposalKind::Connection { .. } => ProposalTag::Connection,
+            ProposalKind::AlreadyConnected { .. } => ProposalTag::AlreadyConnected,
+            ProposalKind::ConnectFailed { .. } => ProposalTag::ConnectFailed,
+            ProposalKind::ResolveFailed { .. } => ProposalTag::ResolveFailed,
+            ProposalKind::OnReadable(..) => ProposalTag::OnReadable,
+            ProposalKind::OnPriority(..) => ProposalTag::OnPriority,
+            ProposalKind::OnWritable(..) => ProposalTag::OnWritable,
+            ProposalKind::WriteDrained(_) => ProposalTag::WriteDrained,
+            ProposalKind::WriteRejected { .. } => ProposalTag::WriteRejected,
+            ProposalKind::Rejected { .. } => ProposalTag::Rejected,
+            ProposalKind::Disconnected { .. } => ProposalTag::Disconnected,
+            ProposalKind::Ready { .. } => ProposalTag::Ready,
+            ProposalKind::Timer(_) => ProposalTag::Timer,
+            ProposalKind::Custom(_) => ProposalTag::Custom,
+        }
+    }
+
+    /// The flat structured form of the proposal for logging, without the io objects.
+    pub fn summary(&self) -> ProposalSummary<'_, Ext> {
+        let mut summary = ProposalSummary {
+            tag: self.tag(),
+            id: None,
+            addr: None,
+            incoming: None,
+            custom: None,
+        };
+        match self {
+            ProposalKind::Wake
+            | ProposalKind::Idle { .. }
+            | ProposalKind::ResolveFailed { .. }
+            | ProposalKind::Timer(_) => {},
+            ProposalKind::Listening { addr }
+            | ProposalKind::ConnectFailed { addr, .. }
+            | ProposalKind::Rejected { addr, .. } => summary.addr = Some(*addr),
+            ProposalKind::Connection {
+                addr, incoming, id, ..
+            } => {
+                summary.id = Some(*id);
+                summary.addr = Some(*addr);
+                summary.incoming = Some(*incoming);
+            },
+            ProposalKind::AlreadyConnected { addr, id }
+            | ProposalKind::OnReadable(id, addr, _)
+            | ProposalKind::OnWritable(id, addr, _)
+            | ProposalKind::OnPriority(id, addr, _)
+            | ProposalKind::Disconnected { id, addr, .. }
+            | ProposalKind::Ready { id, addr, .. } => {
+                summary.id = Some(*id);
+                summary.addr = Some(*addr);
+            },
+            ProposalKind::WriteDrained(id) | ProposalKind::WriteRejected { id, .. } => {
+                summary.id = Some(*id)
+            },
+            ProposalKind::Custom(ext) => summary.custom = Some(ext),
+        }
+        summary
+    }
+
+    /// The copy of the proposal without the io objects.
+    pub fn without_io(&self) -> ProposalKind<(), (), Ext>
+    where
+        Ext: Clone,
+    {
+        match self {
+            ProposalKind::Wake => ProposalKind::Wake,
+            ProposalKind::Idle { timed_out } => ProposalKind::Idle {
+                timed_out: *timed_out,
+            },
+            ProposalKind::Listening { addr } => ProposalKind::Listening { addr: *addr },
+            ProposalKind::Connection {
+                addr,
+                incoming,
+                origin,
+                id,
+            } => ProposalKind::Connection {
+                addr: *addr,
+                incoming: *incoming,
+                origin: *origin,
+                id: *id,
+            },
+            ProposalKind::AlreadyConnected { addr, id } => ProposalKind::AlreadyConnected {
+                addr: *addr,
+                id: *id,
+            },
+            ProposalKind::ConnectFailed { addr, retry_in } => ProposalKind::ConnectFailed {
+                addr: *addr,
+                retry_in: *retry_in,
+            },
+            ProposalKind::ResolveFailed { host, port } => ProposalKind::ResolveFailed {
+                host: host.clone(),
+                port: *port,
+            },
+            ProposalKind::OnReadable(id, addr, _) => ProposalKind::OnReadable(*id, *addr, ()),
+            ProposalKind::OnPriority(id, addr, _) => ProposalKind::OnPriority(*id, *addr, ()),
+            ProposalKind::OnWritable(id, addr, _) => ProposalKind::OnWritable(*id, *addr, ()),
+            ProposalKind::WriteDrained(id) => ProposalKind::WriteDrained(*id),
+            ProposalKind::WriteRejected { id, length } => ProposalKind::WriteRejected {
+                id: *id,
+                length: *length,
+            },
+            ProposalKind::Rejected { addr, reason } => ProposalKind::Rejected {
+                addr: *addr,
+                reason: *reason,
+            },
+            ProposalKind::Disconnected {
+                id,
+                addr,
+                reason,
+                stats,
+            } => ProposalKind::Disconnected {
+                id: *id,
+                addr: *addr,
+                reason: *reason,
+                stats: *stats,
+            },
+            ProposalKind::Ready {
+                id,
+                addr,
+                read,
+                write,
+            } => ProposalKind::Ready {
+                id: *id,
+                addr: *addr,
+                read: read.as_ref().map(|_| ()),
+                write: write.as_ref().map(|_| ()),
+            },
+            ProposalKind::Timer(key) => ProposalKind::Timer(*key),
+            ProposalKind::Custom(ext) => ProposalKind::Custom(ext.clone()),
+        }
+    }
+}
+
 impl<R, W, Ext, Rng> fmt::Display for Proposal<R, W, Ext, Rng>
 where
     Ext: fmt::Display,
@@ -68,19 +634,63 @@ where
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ProposalKind::Wake => write!(f, "wake"),
-            ProposalKind::Idle => write!(f, "idle..."),
+            ProposalKind::Idle { timed_out: true } => write!(f, "idle, timed out..."),
+            ProposalKind::Idle { timed_out: false } => write!(f, "idle, woken up..."),
+            ProposalKind::Listening { addr } => write!(f, "listening at: {}", addr),
             ProposalKind::Connection {
                 addr,
                 incoming: true,
                 id,
+                ..
             } => write!(f, "new incoming connection: {}, addr: {}", id, addr),
             ProposalKind::Connection {
                 addr,
                 incoming: false,
                 id,
+                ..
             } => write!(f, "new outgoing connection: {}, addr: {}", id, addr),
-            ProposalKind::OnReadable(id, _) => write!(f, "local peer can read from {}", id),
-            ProposalKind::OnWritable(id, _) => write!(f, "local peer can write to {}", id),
+            ProposalKind::AlreadyConnected { addr, id } => {
+                write!(f, "already connected: {}, addr: {}", id, addr)
+            },
+            ProposalKind::ConnectFailed { addr, retry_in } => {
+                write!(f, "failed to connect: {}, retry in: {:?}", addr, retry_in)
+            },
+            ProposalKind::ResolveFailed { host, port } => {
+                write!(f, "failed to resolve: {}:{}", host, port)
+            },
+            ProposalKind::OnReadable(id, addr, _) => {
+                write!(f, "local peer can read from {}, addr: {}", id, addr)
+            },
+            ProposalKind::OnWritable(id, addr, _) => {
+                write!(f, "local peer can write to {}, addr: {}", id, addr)
+            },
+            ProposalKind::OnPriority(id, addr, _) => {
+                write!(f, "local peer can read urgent from {}, addr: {}", id, addr)
+            },
+            ProposalKind::WriteDrained(id) => write!(f, "written all queued to {}", id),
+            ProposalKind::WriteRejected { id, length } => {
+                write!(f, "rejected to queue {} bytes to {}", length, id)
+            },
+            ProposalKind::Rejected { addr, reason } => {
+                write!(f, "rejected incoming: {}, {:?}", addr, reason)
+            },
+            ProposalKind::Disconnected {
+                id, addr, reason, ..
+            } => write!(f, "disconnected: {}, addr: {}, {:?}", id, addr, reason),
+            ProposalKind::Ready {
+                id,
+                addr,
+                read,
+                write,
+            } => write!(
+                f,
+                "local peer can{}{} {}, addr: {}",
+                if read.is_some() { " read" } else { "" },
+                if write.is_some() { " write" } else { "" },
+                id,
+                addr
+            ),
+            ProposalKind::Timer(key) => write!(f, "timer is due: {}", key),
             ProposalKind::Custom(ext) => write!(f, "{}", ext),
         }
     }
@@ -91,3 +701,60 @@ impl fmt::Display for ConnectionId {
         write!(f, "{:04x}.{:04x}", self.poll_id, self.token)
     }
 }
+
+#[cfg(all(test, feature = "bytes"))]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::{ReadOnce, IoResult, READ_BUF_CHUNK};
+
+    // gives as much of the data as fits, or `WouldBlock` if it has none
+    struct Short(&'static [u8]);
+
+    impl ReadOnce for Short {
+        fn read(self, buf: &mut [u8]) -> IoResult {
+            if self.0.is_empty() {
+                return IoResult::WouldBlock;
+            }
+            // the reader might leave garbage after the bytes it reports
+            buf.fill(0xff);
+            let length = self.0.len().min(buf.len());
+            buf[..length].clone_from_slice(&self.0[..length]);
+            IoResult::Done {
+                length,
+                will_close: false,
+            }
+        }
+    }
+
+    #[test]
+    fn read_buf_short() {
+        let mut buf = BytesMut::with_capacity(0x100);
+        buf.extend_from_slice(b"abc");
+        let result = Short(b"def").read_buf(&mut buf);
+        assert!(matches!(result, IoResult::Done { length: 3, .. }));
+        assert_eq!(&buf[..], b"abcdef");
+        assert!(buf.capacity() >= 0x100);
+    }
+
+    #[test]
+    fn read_buf_chunk() {
+        static DATA: [u8; 0x10000] = [1; 0x10000];
+
+        let mut buf = BytesMut::with_capacity(0x100000);
+        let result = Short(&DATA).read_buf(&mut buf);
+        // the rest of the capacity is neither filled nor read
+        assert!(matches!(result, IoResult::Done { length, .. } if length == READ_BUF_CHUNK));
+        assert_eq!(buf.len(), READ_BUF_CHUNK);
+        assert!(buf.capacity() >= 0x100000);
+    }
+
+    #[test]
+    fn read_buf_would_block() {
+        let mut buf = BytesMut::with_capacity(0x100);
+        buf.extend_from_slice(b"abc");
+        assert_eq!(Short(b"").read_buf(&mut buf), IoResult::WouldBlock);
+        assert_eq!(&buf[..], b"abc");
+        assert!(buf.capacity() >= 0x100);
+    }
+}
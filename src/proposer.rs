@@ -1,126 +1,1376 @@
 // Copyright 2021 Vladislav Melnik
 // SPDX-License-Identifier: MIT
 
-use std::time::Duration;
-use mio::Events;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+    net::{SocketAddr, IpAddr},
+    io,
+};
+use mio::{Events, Token, event::Event};
+use rand::{SeedableRng, seq::SliceRandom};
+use rand_chacha::ChaCha8Rng;
+use smallvec::{SmallVec, smallvec};
 
 use super::{
-    request::Request,
+    request::{Request, ConnectionSource},
     managed_stream::{TcpReadOnce, TcpWriteOnce},
     state::State,
-    proposal::{ProposalKind, ConnectionId},
-    time::TimeTracker,
-    stream_registry::StreamRegistry,
+    proposal::{
+        ProposalKind, ConnectionId, ConnectionOrigin, ConnectionStats, ConnectionInterest,
+        DisconnectReason,
+    },
+    time::{TimeTracker, Clock},
+    stream_registry::{StreamRegistry, Accepted, Connected, TokenKind},
+    persistent::PersistentConnects,
+    resolver::Resolver,
+    socks5::Socks5Handshake,
     proposer_error::ProposerError,
+    proposer_metrics::ProposerMetrics,
+    proposer_builder::ProposerBuilder,
+    connection_ids::ConnectionIds,
 };
 
+// the listener is reregistered every run, so the connections left
+// in the backlog are reported again by the next poll,
+// the connections dropped by the blacklist or the limits count as well
+const MAX_ACCEPTS_PER_EVENT: usize = 64;
+
+/// The order in which the ready connections are served.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fairness {
+    /// Every run starts from the smallest token,
+    /// the connections with small tokens are served first.
+    #[default]
+    FirstComeFirstServed,
+    /// Every run starts from the token next to the last served in the previous run.
+    RoundRobin,
+}
+
+/// When the proposer sends `Idle`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdlePolicy {
+    /// Whenever the poll gives no events.
+    #[default]
+    Always,
+    /// Only if the poll gives no events and the run served no requests
+    /// and no requests are waiting to be served.
+    Quiescent,
+}
+
+/// How the readiness of the connection is reported.
+/// mio registers the sockets edge triggered on every platform, epoll with `EPOLLET`,
+/// kqueue with `EV_CLEAR`, and Windows emulates it, the native level triggered
+/// registration is not available. So `Level` is emulated by the proposer: the read
+/// which fills the whole buffer is followed by another `OnReadable` without waiting
+/// for the os. The short read is taken as drained, the partial read stalls
+/// if the state reads less than the buffer it passes, use `read_and_keep` then.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// The state receives `OnReadable` only when the new data arrives.
+    #[default]
+    Edge,
+    /// The state receives `OnReadable` while the reads fill the whole buffer.
+    Level,
+}
+
+/// What the proposer does if the state requests to connect to the peer
+/// while the connection with it is in progress, not yet established.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateConnect {
+    /// Propose `AlreadyConnected`, as for the established connection.
+    #[default]
+    Ignore,
+    /// Propose nothing, the state already has the connection in progress.
+    Coalesce,
+    /// Remember the request, connect again if the connection in progress fails,
+    /// or propose `AlreadyConnected` once it is established.
+    Queue,
+    /// Open another connection, even if the established one exists,
+    /// e.g. the control and the data channels with the same server.
+    Allow,
+}
+
+/// What the proposer does if the data queued to the connection would exceed
+/// the limit set with `Request::set_write_buffer_limit`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOverflow {
+    /// Don't queue the data, propose `WriteRejected`.
+    #[default]
+    Reject,
+    /// Disconnect the peer, it does not read fast enough.
+    /// The state receives `Disconnected` if the proposer reports it.
+    Disconnect,
+}
+
 /// The proposer serves the state's requests and provides network events to it.
 pub struct Proposer {
     started: bool,
     request: Request,
     events: Events,
-    id: u16,
+    ready: Vec<Readiness>,
+    // the events before this position are processed
+    ready_pos: usize,
+    raw_event_order: bool,
+    max_proposals_per_run: Option<usize>,
+    fairness: Fairness,
+    idle_policy: IdlePolicy,
+    // the round robin starts from this token
+    cursor: Token,
     stream_registry: StreamRegistry,
+    persistent: PersistentConnects,
+    resolver: Resolver,
+    // the proposer's own random decisions, reproducible for the given seed,
+    // the algorithm is stable, unlike `StdRng`, so is the order across the versions of rand
+    rng: ChaCha8Rng,
+    shuffle_connects: bool,
+    coalesce_ready: bool,
+    report_disconnects: bool,
+    duplicate_connect: DuplicateConnect,
+    write_overflow: WriteOverflow,
+    #[cfg(unix)]
+    urgent_data: bool,
+    // the requests to connect waiting for the connection in progress
+    duplicate_connects: Vec<(SocketAddr, Option<SocketAddr>, Token)>,
+    // the streams adopted since the last run, the state does not know them yet
+    adopted: Vec<(SocketAddr, Token)>,
+    // the connections through the proxy, the state receives them once the tunnel is established
+    socks5: BTreeMap<Token, Socks5Handshake>,
+    // the connect races in progress, the state receives only the winner
+    races: Vec<Race>,
+    ids: ConnectionIds,
+}
+
+struct Race {
+    // reported if every attempt fails
+    first: SocketAddr,
+    // in order of addresses
+    attempts: SmallVec<[(SocketAddr, Token); 4]>,
+    // not tried yet, the next one starts when every attempt failed,
+    // so the resolved addresses of the host are tried one by one
+    queued: SmallVec<[SocketAddr; 4]>,
+}
+
+impl Race {
+    fn contains(&self, token: Token) -> bool {
+        self.attempts.iter().any(|(_, t)| *t == token)
+    }
 }
 
 impl Proposer {
-    /// Set the seed for the random number generator.
+    /// The proposer with the id, distinct for each proposer polled at once,
+    /// and the capacity of the events buffer. The id seeds the random number generator,
+    /// `set_seed` replaces it. See `ProposerBuilder` for more options.
     pub fn new(id: u16, events_capacity: usize) -> Self {
-        Proposer {
+        ProposerBuilder::new()
+            .id(id)
+            .events_capacity(events_capacity)
+            .build()
+            .expect("cannot use non-blocking io")
+    }
+
+    pub(crate) fn with_capacity(id: u16, events_capacity: usize) -> io::Result<Self> {
+        Ok(Proposer {
             started: false,
             request: Request::default(),
             events: Events::with_capacity(events_capacity),
-            id,
-            stream_registry: StreamRegistry::new(),
+            ready: Vec::with_capacity(events_capacity),
+            ready_pos: 0,
+            raw_event_order: false,
+            max_proposals_per_run: None,
+            fairness: Fairness::default(),
+            idle_policy: IdlePolicy::default(),
+            cursor: Token(0),
+            stream_registry: StreamRegistry::new()?,
+            persistent: PersistentConnects::new(),
+            resolver: Resolver::new(),
+            rng: ChaCha8Rng::seed_from_u64(id as u64),
+            shuffle_connects: false,
+            coalesce_ready: false,
+            report_disconnects: false,
+            duplicate_connect: DuplicateConnect::default(),
+            write_overflow: WriteOverflow::default(),
+            #[cfg(unix)]
+            urgent_data: false,
+            duplicate_connects: Vec::new(),
+            adopted: Vec::new(),
+            socks5: BTreeMap::new(),
+            races: Vec::new(),
+            ids: ConnectionIds::new(id),
+        })
+    }
+
+    /// Connect to the requested peers in random order, rather than in the order requested.
+    /// The order is reproducible, the random generator is seeded with the proposer id,
+    /// unless set with `set_seed`.
+    pub fn set_shuffle_connects(&mut self, shuffle: bool) {
+        self.shuffle_connects = shuffle;
+    }
+
+    /// Reseed the random generator of the proposer, it shuffles the connects
+    /// and gives the jitter of the backoff. The same seed gives the same decisions.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = ChaCha8Rng::seed_from_u64(seed);
+    }
+
+    /// Propose `Ready` with both io objects, rather than `OnReadable` and `OnWritable`
+    /// separately, so the state sees the connection both readable and writable at once.
+    pub fn set_coalesce_ready(&mut self, coalesce: bool) {
+        self.coalesce_ready = coalesce;
+    }
+
+    /// Propose `Disconnected` when the connection the state received is gone,
+    /// whether the state requested it or not.
+    pub fn set_report_disconnects(&mut self, report: bool) {
+        self.report_disconnects = report;
+    }
+
+    /// Propose `Rejected` when the incoming connection is dropped because of
+    /// the blacklist or the limits. Off by default, the attack might be noisy.
+    pub fn set_report_rejected(&mut self, report: bool) {
+        self.stream_registry.set_report_rejected(report);
+    }
+
+    /// Do not connect to the blacklisted peers, it is the default. The state receives
+    /// `Rejected` if the proposer reports it, the persistent connection fails instead.
+    /// Disable it to connect to the hosts which are only blacklisted for listening.
+    pub fn set_blacklist_outgoing(&mut self, outgoing: bool) {
+        self.stream_registry.set_blacklist_outgoing(outgoing);
+    }
+
+    /// Give the connections the sequential ids, in order the state receives them,
+    /// rather than the ids derived from the tokens, which depend on the os timing.
+    /// The ids are never reused. Set it before the first connection.
+    pub fn set_sequential_ids(&mut self, sequential: bool) {
+        self.ids.set_sequential(sequential);
+    }
+
+    pub fn set_duplicate_connect(&mut self, policy: DuplicateConnect) {
+        self.duplicate_connect = policy;
+    }
+
+    pub fn set_write_overflow(&mut self, policy: WriteOverflow) {
+        self.write_overflow = policy;
+    }
+
+    /// Check for the urgent (out of band) byte whenever the connection is readable,
+    /// and propose `OnPriority` if the peer sent it. It costs a syscall per readable event.
+    #[cfg(unix)]
+    pub fn set_urgent_data(&mut self, urgent: bool) {
+        self.urgent_data = urgent;
+    }
+
+    pub fn events_capacity(&self) -> usize {
+        self.events.capacity()
+    }
+
+    /// Reallocate the events buffer, the events retained from the previous run are
+    /// kept separately, so they are not lost.
+    pub fn set_events_capacity(&mut self, events_capacity: usize) {
+        self.events = Events::with_capacity(events_capacity);
+    }
+
+    /// Accept the incoming connections from the listener which is bound already,
+    /// for example by the supervisor. Replaces the source the state requested.
+    /// Returns the local address of the listener.
+    pub fn adopt_listener(&mut self, listener: std::net::TcpListener) -> io::Result<SocketAddr> {
+        self.stream_registry.adopt_listener(listener)
+    }
+
+    /// Manage the stream connected already, for example after a handshake
+    /// performed by another library. The state receives the outgoing `Connection`
+    /// on the next run.
+    pub fn adopt_stream(&mut self, stream: std::net::TcpStream) -> io::Result<ConnectionId> {
+        let (addr, token) = self.stream_registry.adopt_stream(stream)?;
+        self.adopted.push((addr, token));
+        Ok(self.connection_id(token))
+    }
+
+    /// Drop incoming connections and refuse to connect if there are this many connections.
+    pub fn set_max_connections(&mut self, max: Option<usize>) {
+        self.stream_registry.set_max_connections(max);
+    }
+
+    /// Count the outgoing connections toward the per ip limit, and refuse to connect
+    /// to the ip which reached the limit.
+    pub fn set_per_ip_limit_outgoing(&mut self, outgoing: bool) {
+        self.stream_registry.set_per_ip_outgoing(outgoing);
+    }
+
+    /// By default the events of a single poll are processed in order of their tokens,
+    /// so the order of proposals is reproducible.
+    /// Process them in the order the os reported instead, it saves a sort.
+    pub fn set_raw_event_order(&mut self, raw: bool) {
+        self.raw_event_order = raw;
+    }
+
+    /// Process at most this many ready events per run, each gives at most two proposals.
+    /// The rest is retained for the next run, which will not poll until it is processed.
+    pub fn set_max_proposals_per_run(&mut self, max: Option<usize>) {
+        self.max_proposals_per_run = max;
+    }
+
+    /// The round robin is useful if the events capacity or the number of proposals per run
+    /// is small, so a few busy connections cannot starve others. It implies the ordered events.
+    pub fn set_fairness(&mut self, fairness: Fairness) {
+        self.fairness = fairness;
+    }
+
+    pub fn set_idle_policy(&mut self, idle_policy: IdlePolicy) {
+        self.idle_policy = idle_policy;
+    }
+
+    /// Dropping an unused `ReadOnce` or `WriteOnce` closes the half of the connection,
+    /// by default it only defers, call `close` to close the half explicitly.
+    pub fn set_close_on_drop(&mut self, close_on_drop: bool) {
+        let mut options = self.stream_registry.options();
+        options.close_on_drop = close_on_drop;
+        self.stream_registry.set_options(options);
+    }
+
+    /// Emulate level triggered readiness, if the read filled the whole buffer
+    /// likely there is more data, the proposer will send `OnReadable` again
+    /// without waiting for the os to report the readiness.
+    pub fn set_level_triggered(&mut self, level_triggered: bool) {
+        let mut options = self.stream_registry.options();
+        options.level_triggered = level_triggered;
+        self.stream_registry.set_options(options);
+    }
+
+    /// Same as `set_level_triggered`, see `Trigger` for the portability caveats.
+    pub fn set_trigger(&mut self, trigger: Trigger) {
+        self.set_level_triggered(trigger == Trigger::Level);
+    }
+
+    /// The source the proposer listens on, the last one applied successfully.
+    /// `ConnectionSource::None` if it does not listen, e.g. the last source failed.
+    /// The adopted listener is reported as `Port` with its port.
+    pub fn current_source(&self) -> ConnectionSource {
+        self.stream_registry.current_source()
+    }
+
+    /// The local address the proposer listens on, the actual port if the source is port zero.
+    pub fn listening_addr(&self) -> Option<SocketAddr> {
+        self.stream_registry.listening_addr()
+    }
+
+    /// The address of the remote peer behind the connection.
+    pub fn addr_of(&self, id: ConnectionId) -> Option<SocketAddr> {
+        self.stream_registry.addr_of(self.token_of_id(id)?)
+    }
+
+    /// How many bytes were read and written over the connection so far.
+    pub fn connection_stats(&self, id: ConnectionId) -> Option<ConnectionStats> {
+        self.stream_registry.stats_of(self.token_of_id(id)?)
+    }
+
+    /// The ip addresses blacklisted so far, in order.
+    pub fn blacklisted_ips(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.stream_registry.blacklisted_ips()
+    }
+
+    /// The counters accumulated since the proposer is created or since the last reset.
+    pub fn metrics(&self) -> ProposerMetrics {
+        self.stream_registry.metrics()
+    }
+
+    /// Zero the counters, the number of live connections is not affected.
+    pub fn reset_metrics(&mut self) {
+        self.stream_registry.reset_metrics()
+    }
+
+    /// How many bytes queued with `Request::queue_write` are not written yet.
+    pub fn queued_len(&self, id: ConnectionId) -> Option<usize> {
+        self.stream_registry.queued_len(self.token_of_id(id)?)
+    }
+
+    /// How many frames queued with `Request::send_frame` are not completely written yet.
+    pub fn queued_frames(&self, id: ConnectionId) -> Option<usize> {
+        self.stream_registry.queued_frames(self.token_of_id(id)?)
+    }
+
+    /// What the proposer is waiting for on the connection, for debugging.
+    pub fn interest_of(&self, id: ConnectionId) -> Option<ConnectionInterest> {
+        self.stream_registry.interest_of(self.token_of_id(id)?)
+    }
+
+    /// The file descriptor of the connection, e.g. to set the socket option
+    /// the proposer does not provide. Do not close it, the proposer owns it.
+    #[cfg(unix)]
+    pub fn raw_fd(&self, id: ConnectionId) -> Option<std::os::unix::io::RawFd> {
+        self.stream_registry.raw_fd(self.token_of_id(id)?)
+    }
+
+    /// The socket of the connection, e.g. to set the socket option
+    /// the proposer does not provide. Do not close it, the proposer owns it.
+    #[cfg(windows)]
+    pub fn raw_socket(&self, id: ConnectionId) -> Option<std::os::windows::io::RawSocket> {
+        self.stream_registry.raw_socket(self.token_of_id(id)?)
+    }
+
+    /// The connection with the remote peer at the given address,
+    /// the oldest one if there are several.
+    pub fn id_of(&self, addr: SocketAddr) -> Option<ConnectionId> {
+        self.stream_registry
+            .token_of(&addr)
+            .and_then(|token| self.ids.known(token))
+    }
+
+    /// Disconnect every connection the predicate holds for, returns how many are closed.
+    /// The connections the state did not receive yet are not affected.
+    /// The state receives `Disconnected` on the next run if the proposer reports it.
+    #[allow(clippy::result_large_err)]
+    pub fn close_where<F>(&mut self, pred: F) -> Result<usize, ProposerError>
+    where
+        F: Fn(ConnectionId, SocketAddr) -> bool,
+    {
+        let mut closed = 0;
+        for (token, addr) in self.stream_registry.peers() {
+            let unknown = self.socks5.contains_key(&token)
+                || self.races.iter().any(|race| race.contains(token))
+                || self.adopted.iter().any(|(_, t)| *t == token)
+                || self.persistent.connecting(token).is_some();
+            if unknown {
+                continue;
+            }
+            let id = match self.ids.known(token) {
+                Some(id) => id,
+                None => continue,
+            };
+            if pred(id, addr) {
+                self.stream_registry
+                    .disconnect_peer(token, DisconnectReason::Requested);
+                closed += 1;
+            }
+        }
+        self.stream_registry.take_result().map(|()| closed)
+    }
+
+    /// The caller's timeout, shortened so the internal deadlines are met on time.
+    /// Without any deadline it is the caller's timeout as is.
+    fn poll_timeout(
+        &self,
+        timeout: Duration,
+        now: Instant,
+        wake_now: bool,
+        accept_resume: Option<Instant>,
+        next_timer: Option<Instant>,
+    ) -> Duration {
+        // don't wait if some reader asked to be polled again, or the state asked to wake
+        if wake_now || self.stream_registry.has_pending_readers() {
+            return Duration::ZERO;
         }
+        let deadlines = [self.persistent.next_due(), accept_resume, next_timer];
+        deadlines
+            .iter()
+            .flatten()
+            .fold(timeout, |timeout, deadline| {
+                timeout.min(deadline.saturating_duration_since(now))
+            })
+    }
+
+    /// Returns whether any timer fired.
+    fn fire_timers<Rngs, S, C>(
+        &mut self,
+        time_tracker: &mut TimeTracker<Rngs, S, TcpReadOnce, TcpWriteOnce, C>,
+    ) -> bool
+    where
+        Rngs: Iterator<Item = S::Rng>,
+        S: State<TcpReadOnce, TcpWriteOnce>,
+        C: Clock,
+    {
+        let due = time_tracker.take_due_timers();
+        for &key in &due {
+            self.request += propose(time_tracker, ProposalKind::Timer(key));
+        }
+        !due.is_empty()
+    }
+
+    fn token_of_id(&self, id: ConnectionId) -> Option<Token> {
+        self.ids.token(id)
+    }
+
+    fn connection_id(&mut self, token: Token) -> ConnectionId {
+        self.ids.id(token)
+    }
+
+    /// Run iterations until `should_stop` returns true, checked before each iteration.
+    /// Returns the first error.
+    #[allow(clippy::result_large_err)]
+    pub fn run_until<Rngs, S, C, F>(
+        &mut self,
+        time_tracker: &mut TimeTracker<Rngs, S, TcpReadOnce, TcpWriteOnce, C>,
+        timeout: Duration,
+        should_stop: F,
+    ) -> Result<(), ProposerError>
+    where
+        Rngs: Iterator<Item = S::Rng>,
+        S: State<TcpReadOnce, TcpWriteOnce>,
+        C: Clock,
+        F: FnMut(&S) -> bool,
+    {
+        let mut should_stop = should_stop;
+        while !should_stop(time_tracker.as_ref()) {
+            self.run(time_tracker, timeout)?;
+        }
+        Ok(())
     }
 
     /// Run the single iteration
-    pub fn run<Rngs, S>(
+    #[allow(clippy::result_large_err)]
+    pub fn run<Rngs, S, C>(
         &mut self,
-        time_tracker: &mut TimeTracker<Rngs, S, TcpReadOnce, TcpWriteOnce>,
+        time_tracker: &mut TimeTracker<Rngs, S, TcpReadOnce, TcpWriteOnce, C>,
         timeout: Duration,
     ) -> Result<(), ProposerError>
     where
         Rngs: Iterator<Item = S::Rng>,
         S: State<TcpReadOnce, TcpWriteOnce>,
+        C: Clock,
     {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("run", poll_id = self.ids.poll_id()).entered();
+
         if !self.started {
             self.started = true;
-            self.request += time_tracker.send(ProposalKind::Wake);
-            return Ok(());
+            self.request += propose(time_tracker, ProposalKind::Wake);
+            return self.take_result(time_tracker.rng_exhausted());
+        }
+        if self.request.take_wake() {
+            self.request += propose(time_tracker, ProposalKind::Wake);
         }
 
+        let wake_now = self.request.take_wake_now();
+        let served = !self.request.is_empty();
+
         if let Some(source) = self.request.take_new_source() {
-            self.stream_registry.set_source(source);
+            if let Some(addr) = self.stream_registry.set_source(source) {
+                self.request += propose(time_tracker, ProposalKind::Listening { addr });
+            }
+        }
+
+        for (addr, token) in std::mem::take(&mut self.adopted) {
+            let id = self.connection_id(token);
+            let kind = ProposalKind::Connection {
+                addr,
+                incoming: false,
+                origin: ConnectionOrigin::Adopted,
+                id,
+            };
+            self.request += propose(time_tracker, kind);
+        }
+
+        if let Some(limit) = self.request.take_per_ip_limit() {
+            self.stream_registry.set_per_ip_limit(Some(limit));
+        }
+        let now = time_tracker.clock().now();
+        if let Some((per_sec, burst)) = self.request.take_accept_rate() {
+            self.stream_registry.set_accept_rate(per_sec, burst, now);
+        }
+        let accept_resume = self.stream_registry.refill_accept_rate(now);
+
+        for id in self.request.take_reserved_ids() {
+            let reserved = self
+                .token_of_id(id)
+                .map(|token| self.stream_registry.reserve_token(token))
+                .unwrap_or(false);
+            if !reserved {
+                log::warn!("cannot reserve the id: {}, it is unknown", id);
+            }
+        }
+        for id in self.request.take_released_ids() {
+            if let Some(token) = self.token_of_id(id) {
+                self.stream_registry.release_reserved_token(token);
+            }
+        }
+
+        for addr in self.request.take_disconnects() {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(%addr, "disconnect");
+            // every connection with the address
+            for token in self.stream_registry.tokens_of(&addr) {
+                self.stream_registry
+                    .disconnect_peer(token, DisconnectReason::Requested);
+            }
+        }
+        for id in self.request.take_disconnect_ids() {
+            if let Some(token) = self.token_of_id(id) {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(%id, addr = ?self.addr_of(id), "disconnect");
+                self.stream_registry
+                    .disconnect_peer(token, DisconnectReason::Requested);
+            }
+        }
+
+        for id in self.request.take_resets() {
+            if let Some(token) = self.token_of_id(id) {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(%id, addr = ?self.addr_of(id), "reset");
+                self.stream_registry.reset_peer(token);
+            }
+        }
+
+        for (id, how) in self.request.take_shutdowns() {
+            if let Some(token) = self.token_of_id(id) {
+                self.stream_registry.shutdown_peer(token, how);
+            }
+        }
+        for (id, paused) in self.request.take_read_pauses() {
+            if let Some(token) = self.token_of_id(id) {
+                self.stream_registry.pause_read(token, paused);
+            }
+        }
+        for (id, paused) in self.request.take_write_pauses() {
+            if let Some(token) = self.token_of_id(id) {
+                self.stream_registry.pause_write(token, paused);
+            }
+        }
+        for id in self.request.take_arm_writes() {
+            if let Some(token) = self.token_of_id(id) {
+                self.stream_registry.arm_write(token);
+            }
         }
 
         for addr in self.request.take_blacklist() {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(%addr, "blacklist");
             self.stream_registry.blacklist_peer(addr);
         }
+        for ip in self.request.take_blacklist_ips() {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(%ip, "blacklist");
+            self.stream_registry.blacklist_ip(ip);
+        }
+        for id in self.request.take_blacklist_ids() {
+            if let Some(addr) = self.addr_of(id) {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(%id, %addr, "blacklist");
+                self.stream_registry.blacklist_peer(addr);
+            }
+        }
+
+        for (id, limit) in self.request.take_write_buffer_limits() {
+            if let Some(token) = self.token_of_id(id) {
+                self.stream_registry.set_queue_limit(token, limit);
+            }
+        }
+        for (id, data, frame, now) in self.request.take_queued_writes() {
+            let token = match self.token_of_id(id) {
+                Some(token) => token,
+                None => continue,
+            };
+            if self.stream_registry.queue_write(token, &data, frame) {
+                // the rest is written when the connection is writable,
+                // the queue keeps the writable interest
+                if now
+                    && self.stream_registry.is_established(token)
+                    && self.stream_registry.flush_queued(token) == Some(true)
+                {
+                    self.request += propose(time_tracker, ProposalKind::WriteDrained(id));
+                }
+                continue;
+            }
+            match self.write_overflow {
+                WriteOverflow::Disconnect if !self.stream_registry.queue_closed(token) => {
+                    log::warn!("the write buffer is full, disconnect: {}", id);
+                    self.stream_registry
+                        .disconnect_peer(token, DisconnectReason::WriteBufferFull);
+                },
+                // the stream closing or shut down for writing is rejected regardless
+                _ => {
+                    let length = data.len();
+                    let kind = ProposalKind::WriteRejected { id, length };
+                    self.request += propose(time_tracker, kind);
+                },
+            }
+        }
+
+        for (id, length) in self.request.take_rejected_frames() {
+            let kind = ProposalKind::WriteRejected { id, length };
+            self.request += propose(time_tracker, kind);
+        }
+
+        // the goodbye goes after the writes queued by this request
+        for (addr, goodbye) in self.request.take_blacklist_after_flush() {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(%addr, "blacklist after flush");
+            self.stream_registry.blacklist_after_flush(addr, &goodbye);
+        }
 
         self.stream_registry.reregister();
 
-        for addr in self.request.take_connects() {
-            if let Some(token) = self.stream_registry.connect_peer(addr) {
-                let kind = ProposalKind::Connection {
+        let disconnected = self
+            .stream_registry
+            .take_disconnected()
+            .collect::<SmallVec<[_; 4]>>();
+        if self.report_disconnects {
+            for (token, addr, reason, stats) in disconnected {
+                let kind = ProposalKind::Disconnected {
+                    id: self.connection_id(token),
+                    addr,
+                    reason,
+                    stats,
+                };
+                self.request += propose(time_tracker, kind);
+            }
+        }
+
+        let mut retries = SmallVec::<[_; 4]>::new();
+        for (addr, local, token) in std::mem::take(&mut self.duplicate_connects) {
+            if self.stream_registry.addr_of(token) != Some(addr) {
+                // the connection in progress failed
+                retries.push((addr, local));
+            } else if self.stream_registry.is_established(token) {
+                let id = self.connection_id(token);
+                let kind = ProposalKind::AlreadyConnected { addr, id };
+                self.request += propose(time_tracker, kind);
+            } else {
+                self.duplicate_connects.push((addr, local, token));
+            }
+        }
+
+        let connects = self.request.take_connects().map(|addr| (addr, None));
+        let connects_from = self
+            .request
+            .take_connects_from()
+            .map(|(local, addr)| (addr, Some(local)));
+        let mut connects = connects
+            .chain(connects_from)
+            .chain(retries)
+            .collect::<SmallVec<[_; 8]>>();
+        if self.shuffle_connects {
+            connects.shuffle(&mut self.rng);
+        }
+        let dedup = self.duplicate_connect != DuplicateConnect::Allow;
+        for (addr, local) in connects {
+            let kind = match self.stream_registry.connect_peer(addr, local, dedup) {
+                Some(Connected::New(token)) => ProposalKind::Connection {
                     addr,
                     incoming: false,
-                    id: ConnectionId {
-                        poll_id: self.id,
-                        token: token.0 as u16,
+                    origin: ConnectionOrigin::Outgoing,
+                    id: self.connection_id(token),
+                },
+                Some(Connected::Existing(token)) if !self.stream_registry.is_established(token) => {
+                    match self.duplicate_connect {
+                        DuplicateConnect::Ignore | DuplicateConnect::Allow => {
+                            ProposalKind::AlreadyConnected {
+                                addr,
+                                id: self.connection_id(token),
+                            }
+                        },
+                        DuplicateConnect::Coalesce => continue,
+                        DuplicateConnect::Queue => {
+                            self.duplicate_connects.push((addr, local, token));
+                            continue;
+                        },
+                    }
+                },
+                Some(Connected::Existing(token)) => ProposalKind::AlreadyConnected {
+                    addr,
+                    id: self.connection_id(token),
+                },
+                None => continue,
+            };
+            self.request += propose(time_tracker, kind);
+        }
+
+        for (id, addr) in self.request.take_reconnects() {
+            let reconnected = match self.token_of_id(id) {
+                Some(token) => self.stream_registry.reconnect_peer(token, addr),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "the id is unknown")),
+            };
+            let kind = match reconnected {
+                Ok(_) => ProposalKind::Connection {
+                    addr,
+                    incoming: false,
+                    origin: ConnectionOrigin::Outgoing,
+                    id,
+                },
+                Err(e) => {
+                    log::warn!("failed to reconnect: {}, {}, error: {}", id, addr, e);
+                    ProposalKind::ConnectFailed {
+                        addr,
+                        retry_in: Duration::ZERO,
+                    }
+                },
+            };
+            self.request += propose(time_tracker, kind);
+        }
+
+        for mut addrs in self.request.take_connect_races() {
+            addrs.sort();
+            addrs.dedup();
+            let first = match addrs.first() {
+                Some(addr) => *addr,
+                None => continue,
+            };
+            let mut attempts = SmallVec::new();
+            for addr in addrs {
+                // every attempt is a new connection, the race gives the single one
+                match self.stream_registry.try_connect_peer(addr, None, false) {
+                    Ok(Connected::New(token)) | Ok(Connected::Existing(token)) => {
+                        attempts.push((addr, token))
                     },
+                    Err(e) => log::warn!("failed to connect: {}, error: {}", addr, e),
+                }
+            }
+            if attempts.is_empty() {
+                let kind = ProposalKind::ConnectFailed {
+                    addr: first,
+                    retry_in: Duration::ZERO,
                 };
-                self.request += time_tracker.send(kind);
+                self.request += propose(time_tracker, kind);
+            } else {
+                self.races.push(Race {
+                    first,
+                    attempts,
+                    queued: SmallVec::new(),
+                });
             }
         }
 
-        self.stream_registry.poll(&mut self.events, timeout);
+        let registry = &self.stream_registry;
+        self.socks5
+            .retain(|token, _| registry.addr_of(*token).is_some());
+        for (proxy, target) in self.request.take_connects_via_socks5() {
+            let kind = match self.stream_registry.try_connect_via(proxy, target, dedup) {
+                Ok(Connected::New(token)) => {
+                    self.socks5.insert(token, Socks5Handshake::new(target));
+                    continue;
+                },
+                Ok(Connected::Existing(token)) => ProposalKind::AlreadyConnected {
+                    addr: target,
+                    id: self.connection_id(token),
+                },
+                Err(e) => {
+                    log::warn!("failed to connect to the proxy: {}, error: {}", proxy, e);
+                    ProposalKind::ConnectFailed {
+                        addr: target,
+                        retry_in: Duration::ZERO,
+                    }
+                },
+            };
+            self.request += propose(time_tracker, kind);
+        }
 
-        if self.events.is_empty() {
-            self.request += time_tracker.send(ProposalKind::Idle);
+        for (host, port) in self.request.take_connect_hosts() {
+            let waker = self.stream_registry.waker();
+            self.resolver.resolve(host, port, waker);
         }
-        for event in self.events.into_iter() {
-            if event.token() == StreamRegistry::LISTENER {
-                while let Some((addr, token)) = self.stream_registry.accept() {
-                    let kind = ProposalKind::Connection {
-                        addr,
-                        incoming: true,
-                        id: ConnectionId {
-                            poll_id: self.id,
-                            token: token.0 as u16,
-                        },
+        while let Some((host, port, result)) = self.resolver.try_recv() {
+            let addrs = result.unwrap_or_default();
+            let first = match addrs.first() {
+                Some(addr) => *addr,
+                None => {
+                    self.request +=
+                        propose(time_tracker, ProposalKind::ResolveFailed { host, port });
+                    continue;
+                },
+            };
+            // the first address which initiates the connection, the rest are queued in the race
+            let mut queued = addrs.into_iter().collect::<SmallVec<[_; 4]>>();
+            let kind = match self.stream_registry.connect_next(&mut queued, dedup) {
+                Some((addr, Connected::New(token))) => {
+                    self.races.push(Race {
+                        first,
+                        attempts: smallvec![(addr, token)],
+                        queued,
+                    });
+                    continue;
+                },
+                Some((addr, Connected::Existing(token))) => ProposalKind::AlreadyConnected {
+                    addr,
+                    id: self.connection_id(token),
+                },
+                None => ProposalKind::ConnectFailed {
+                    addr: first,
+                    retry_in: Duration::ZERO,
+                },
+            };
+            self.request += propose(time_tracker, kind);
+        }
+
+        let now = time_tracker.clock().now();
+        for (addr, backoff) in self.request.take_persistent_connects() {
+            self.persistent.add(addr, backoff, now);
+        }
+        for addr in self.request.take_persistent_cancels() {
+            if let Some(token) = self.persistent.cancel(addr) {
+                self.stream_registry.abort_peer(token);
+            }
+        }
+        let registry = &self.stream_registry;
+        self.persistent
+            .check_alive(now, |token| registry.addr_of(token).is_some());
+        for addr in self.persistent.take_due(now) {
+            // the persistent connection is a single one, it adopts the existing
+            match self.stream_registry.try_connect_peer(addr, None, true) {
+                Ok(Connected::New(token)) => self.persistent.attach(addr, token, false),
+                Ok(Connected::Existing(token)) => self.persistent.attach(addr, token, true),
+                Err(e) => {
+                    log::warn!("failed to connect: {}, error: {}", addr, e);
+                    let retry_in = self.persistent.failed(addr, now, &mut self.rng);
+                    let kind = ProposalKind::ConnectFailed { addr, retry_in };
+                    self.request += propose(time_tracker, kind);
+                },
+            }
+        }
+
+        for (id, option) in self.request.take_socket_options() {
+            if let Some(token) = self.token_of_id(id) {
+                self.stream_registry.set_socket_option(token, option);
+            }
+        }
+
+        // don't poll until the events retained by the previous run are processed
+        if self.ready_pos == self.ready.len() {
+            let pending = self.stream_registry.has_pending_readers();
+            let now = time_tracker.clock().now();
+            let next_timer = time_tracker.next_timer();
+            let timeout = self.poll_timeout(timeout, now, wake_now, accept_resume, next_timer);
+            // the immediate wake returns at once, nothing timed out
+            let timed_out = self.stream_registry.poll(&mut self.events, timeout) && !wake_now;
+
+            let quiescent = !served && self.request.is_empty();
+            let idle = match self.idle_policy {
+                IdlePolicy::Always => true,
+                IdlePolicy::Quiescent => quiescent,
+            };
+            let fired = self.fire_timers(time_tracker);
+            if self.events.is_empty() && !pending && idle && !fired {
+                self.request += propose(time_tracker, ProposalKind::Idle { timed_out });
+            }
+
+            self.ready.clear();
+            self.ready_pos = 0;
+            self.ready.extend(self.events.iter().map(Readiness::from));
+        } else {
+            self.fire_timers(time_tracker);
+        }
+
+        let start = self.ready_pos;
+        for token in self.stream_registry.pending_readers() {
+            match self.ready[start..].iter_mut().find(|e| e.token == token) {
+                Some(event) => event.readable = true,
+                None => self.ready.push(Readiness::readable(token)),
+            }
+        }
+        if !self.raw_event_order || self.fairness == Fairness::RoundRobin {
+            // the listener has the greatest token, so it goes last
+            self.ready[start..].sort_by_key(|event| event.token);
+        }
+        if self.fairness == Fairness::RoundRobin {
+            let cursor = self.cursor;
+            let ready = &mut self.ready[start..];
+            if let Some(pos) = ready.iter().position(|event| event.token >= cursor) {
+                ready.rotate_left(pos);
+            }
+        }
+        let end = match self.max_proposals_per_run {
+            Some(max) => self.ready.len().min(start + max.max(1)),
+            None => self.ready.len(),
+        };
+        self.ready_pos = end;
+        if let Some(last) = self.ready[start..end]
+            .iter()
+            .rev()
+            .find(|event| StreamRegistry::classify(event.token) == TokenKind::Connection)
+        {
+            self.cursor = Token(last.token.0 + 1);
+        }
+        // settle the races before the events are dispatched,
+        // so the losers are closed and the winner is served as usual
+        let ready = &self.ready[start..end];
+        let registry = &mut self.stream_registry;
+        let ids = &mut self.ids;
+        let mut settled = SmallVec::<[_; 4]>::new();
+        self.races.retain_mut(|race| {
+            if !ready.iter().any(|event| race.contains(event.token)) {
+                return true;
+            }
+            let mut winner = None;
+            race.attempts.retain(|&mut (addr, token)| {
+                if winner.is_some() {
+                    return true;
+                }
+                match registry.is_connected(token) {
+                    Ok(false) => true,
+                    Ok(true) => {
+                        winner = Some((addr, token));
+                        false
+                    },
+                    Err(e) => {
+                        log::warn!("failed to connect: {}, error: {}", addr, e);
+                        registry.count_connect_failure();
+                        registry.abort_peer(token);
+                        false
+                    },
+                }
+            });
+            if let Some((addr, token)) = winner {
+                for (_, loser) in race.attempts.drain(..) {
+                    registry.abort_peer(loser);
+                }
+                let id = ids.id(token);
+                settled.push(ProposalKind::Connection {
+                    addr,
+                    incoming: false,
+                    origin: ConnectionOrigin::Outgoing,
+                    id,
+                });
+                false
+            } else if !race.attempts.is_empty() {
+                true
+            } else if let Some((addr, connected)) = registry.connect_next(&mut race.queued, false) {
+                match connected {
+                    Connected::New(token) | Connected::Existing(token) => {
+                        race.attempts.push((addr, token))
+                    },
+                }
+                true
+            } else {
+                settled.push(ProposalKind::ConnectFailed {
+                    addr: race.first,
+                    retry_in: Duration::ZERO,
+                });
+                false
+            }
+        });
+        for kind in settled {
+            self.request += propose(time_tracker, kind);
+        }
+
+        for event in &self.ready[start..end] {
+            // the state does not know the connection racing with others
+            if self.races.iter().any(|race| race.contains(event.token)) {
+                continue;
+            }
+            // the connection through the proxy is reported when the tunnel is established
+            if let Some(handshake) = self.socks5.get_mut(&event.token) {
+                let addr = handshake.target();
+                match self.stream_registry.socks5_step(event.token, handshake) {
+                    Ok(false) => continue,
+                    Ok(true) => {
+                        self.socks5.remove(&event.token);
+                        let id = self.ids.id(event.token);
+                        let kind = ProposalKind::Connection {
+                            addr,
+                            incoming: false,
+                            origin: ConnectionOrigin::Outgoing,
+                            id,
+                        };
+                        self.request += propose(time_tracker, kind);
+                    },
+                    Err(e) => {
+                        log::warn!(
+                            "failed to connect through the proxy: {}, error: {}",
+                            addr,
+                            e
+                        );
+                        self.socks5.remove(&event.token);
+                        self.stream_registry.count_connect_failure();
+                        self.stream_registry.abort_peer(event.token);
+                        let kind = ProposalKind::ConnectFailed {
+                            addr,
+                            retry_in: Duration::ZERO,
+                        };
+                        self.request += propose(time_tracker, kind);
+                        continue;
+                    },
+                }
+            }
+            // the persistent connection is reported when it is established
+            if let Some(addr) = self.persistent.connecting(event.token) {
+                match self.stream_registry.is_connected(event.token) {
+                    Ok(false) => continue,
+                    Ok(true) => {
+                        self.persistent.established(addr);
+                        let id = self.ids.id(event.token);
+                        let kind = ProposalKind::Connection {
+                            addr,
+                            incoming: false,
+                            origin: ConnectionOrigin::Outgoing,
+                            id,
+                        };
+                        self.request += propose(time_tracker, kind);
+                    },
+                    Err(e) => {
+                        log::warn!("failed to connect: {}, error: {}", addr, e);
+                        self.stream_registry.count_connect_failure();
+                        self.stream_registry.abort_peer(event.token);
+                        let now = time_tracker.clock().now();
+                        let retry_in = self.persistent.failed(addr, now, &mut self.rng);
+                        let kind = ProposalKind::ConnectFailed { addr, retry_in };
+                        self.request += propose(time_tracker, kind);
+                        continue;
+                    },
+                }
+            }
+            let (addr, stream) = match StreamRegistry::classify(event.token) {
+                TokenKind::Listener => {
+                    // nothing to accept without the listener
+                    let listener = match self.stream_registry.listening_addr() {
+                        Some(listener) => listener,
+                        None => continue,
                     };
-                    self.request += time_tracker.send(kind);
+                    for _ in 0..MAX_ACCEPTS_PER_EVENT {
+                        let (addr, token) = match self.stream_registry.accept() {
+                            Some(Accepted::New(addr, token)) => (addr, token),
+                            Some(Accepted::Dropped) => continue,
+                            None => break,
+                        };
+                        let kind = ProposalKind::Connection {
+                            addr,
+                            incoming: true,
+                            origin: ConnectionOrigin::Listener(listener),
+                            id: self.ids.id(token),
+                        };
+                        self.request += propose(time_tracker, kind);
+                    }
+                    for (addr, reason) in self.stream_registry.take_rejected() {
+                        let kind = ProposalKind::Rejected { addr, reason };
+                        self.request += propose(time_tracker, kind);
+                    }
+                    continue;
+                },
+                // the waker only interrupts the poll
+                TokenKind::Waker => continue,
+                TokenKind::Connection => match self.stream_registry.take_stream(&event.token) {
+                    Some(taken) => taken,
+                    None => continue,
+                },
+            };
+            let id = self.ids.id(stream.token());
+            let mut write = None;
+            if event.writable {
+                let drained = stream.flush();
+                if drained == Some(true) {
+                    self.request += propose(time_tracker, ProposalKind::WriteDrained(id));
                 }
-            } else if let Some((_, stream)) = self.stream_registry.take_stream(&event.token()) {
-                let id = ConnectionId {
-                    poll_id: self.id,
-                    token: stream.token().0 as u16,
-                };
-                if event.is_writable() {
+                // the state can write once the queue is drained, unless it paused writing
+                if drained != Some(false) && !stream.write_paused() {
+                    // none if the state holds the writer, or the event is stale
                     if let Some(w) = stream.write_once() {
-                        if event.is_write_closed() {
+                        if event.write_closed {
                             stream.set_write_closed();
                         }
-                        self.request += time_tracker.send(ProposalKind::OnWritable(id, w));
-                    } else {
-                        debug_assert!(false, "mio should not poll for this event");
+                        write = Some(w);
                     }
                 }
-                if event.is_readable() {
-                    if let Some(r) = stream.read_once() {
-                        if event.is_read_closed() {
-                            stream.set_read_closed();
-                        }
-                        self.request += time_tracker.send(ProposalKind::OnReadable(id, r));
-                    } else {
-                        debug_assert!(false, "mio should not poll for this event");
+            }
+            // the urgent byte goes ahead of the regular data
+            #[cfg(unix)]
+            if self.urgent_data && (event.readable || event.priority) {
+                if let Some(r) = stream.urgent_once() {
+                    self.request += propose(time_tracker, ProposalKind::OnPriority(id, addr, r));
+                }
+            }
+            let mut read = None;
+            // the event might be reported before the reading is paused,
+            // or retained from the previous run and the read half is shut down meanwhile
+            if event.readable && !stream.read_paused() {
+                if let Some(r) = stream.read_once() {
+                    if event.read_closed {
+                        stream.set_read_closed();
                     }
+                    read = Some(r);
+                }
+            }
+            if self.coalesce_ready {
+                if read.is_some() || write.is_some() {
+                    let kind = ProposalKind::Ready {
+                        id,
+                        addr,
+                        read,
+                        write,
+                    };
+                    self.request += propose(time_tracker, kind);
+                }
+            } else {
+                if let Some(w) = write {
+                    self.request += propose(time_tracker, ProposalKind::OnWritable(id, addr, w));
+                }
+                if let Some(r) = read {
+                    self.request += propose(time_tracker, ProposalKind::OnReadable(id, addr, r));
                 }
             }
         }
 
-        self.stream_registry.take_result()
+        let recycled = self.stream_registry.recycle_tokens();
+        self.ids.release(recycled);
+        self.take_result(time_tracker.rng_exhausted())
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn take_result(&mut self, rng_exhausted: bool) -> Result<(), ProposerError> {
+        match self.stream_registry.take_result() {
+            Err(mut error) => {
+                error.rng_exhausted = rng_exhausted;
+                Err(error)
+            },
+            Ok(()) if rng_exhausted => Err(ProposerError {
+                rng_exhausted,
+                ..ProposerError::default()
+            }),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Write the queued data until every queue is drained or the deadline passes,
+    /// returns whether every queue is drained. The state receives no proposals,
+    /// the events polled meanwhile are retained for the next run.
+    /// For the lossless stop, request `shutdown_write` after the data is queued,
+    /// the write half is shut down once its queue is drained, then drain before exit.
+    #[allow(clippy::result_large_err)]
+    pub fn drain_writes(&mut self, deadline: Instant) -> Result<bool, ProposerError> {
+        // the events retained by the previous run are consumed already
+        let mut writable = self.ready[self.ready_pos..]
+            .iter()
+            .filter(|event| event.writable)
+            .map(|event| event.token)
+            .collect::<SmallVec<[_; 8]>>();
+        loop {
+            for token in writable.drain(..) {
+                self.stream_registry.flush_queued(token);
+            }
+            if !self.stream_registry.has_queued() {
+                return Ok(true);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            self.stream_registry.poll(&mut self.events, deadline - now);
+            self.stream_registry.take_result()?;
+
+            let start = self.ready_pos;
+            for readiness in self.events.iter().map(Readiness::from) {
+                if readiness.writable {
+                    writable.push(readiness.token);
+                }
+                match self.ready[start..]
+                    .iter_mut()
+                    .find(|e| e.token == readiness.token)
+                {
+                    Some(event) => event.merge(readiness),
+                    None => self.ready.push(readiness),
+                }
+            }
+        }
     }
 }
+
+/// Send the proposal to the state, with the `tracing` feature trace it first.
+fn propose<Rngs, S, C>(
+    time_tracker: &mut TimeTracker<Rngs, S, TcpReadOnce, TcpWriteOnce, C>,
+    kind: ProposalKind<TcpReadOnce, TcpWriteOnce, S::Ext>,
+) -> Request
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<TcpReadOnce, TcpWriteOnce>,
+    C: Clock,
+{
+    #[cfg(feature = "tracing")]
+    match &kind {
+        ProposalKind::Connection {
+            addr,
+            incoming,
+            origin,
+            id,
+        } => {
+            tracing::debug!(%id, %addr, incoming, ?origin, "connection");
+        },
+        ProposalKind::OnReadable(id, addr, _) => {
+            tracing::trace!(%id, %addr, "proposal: readable");
+        },
+        ProposalKind::OnWritable(id, addr, _) => {
+            tracing::trace!(%id, %addr, "proposal: writable");
+        },
+        ProposalKind::OnPriority(id, addr, _) => {
+            tracing::debug!(%id, %addr, "proposal: priority");
+        },
+        ProposalKind::AlreadyConnected { addr, id } => {
+            tracing::debug!(%id, %addr, "already connected");
+        },
+        ProposalKind::ConnectFailed { addr, retry_in } => {
+            tracing::debug!(%addr, ?retry_in, "connect failed");
+        },
+        ProposalKind::ResolveFailed { host, port } => {
+            tracing::debug!(%host, port, "resolve failed");
+        },
+        ProposalKind::Wake => tracing::trace!("proposal: wake"),
+        ProposalKind::Idle { timed_out } => tracing::trace!(timed_out, "proposal: idle"),
+        ProposalKind::Listening { addr } => tracing::debug!(%addr, "listening"),
+        ProposalKind::WriteDrained(id) => tracing::trace!(%id, "proposal: write drained"),
+        ProposalKind::WriteRejected { id, length } => {
+            tracing::debug!(%id, length, "proposal: write rejected");
+        },
+        ProposalKind::Rejected { addr, reason } => {
+            tracing::debug!(%addr, ?reason, "rejected");
+        },
+        ProposalKind::Disconnected {
+            id, addr, reason, ..
+        } => {
+            tracing::debug!(%id, %addr, ?reason, "disconnected");
+        },
+        ProposalKind::Ready {
+            id,
+            addr,
+            read,
+            write,
+        } => {
+            let (readable, writable) = (read.is_some(), write.is_some());
+            tracing::trace!(%id, %addr, readable, writable, "proposal: ready");
+        },
+        ProposalKind::Timer(key) => tracing::trace!(key, "proposal: timer"),
+        ProposalKind::Custom(_) => tracing::trace!("proposal: custom"),
+    }
+    time_tracker.send(kind)
+}
+
+/// The copy of the `mio::event::Event`, which can be sorted.
+struct Readiness {
+    token: Token,
+    readable: bool,
+    writable: bool,
+    read_closed: bool,
+    write_closed: bool,
+    priority: bool,
+}
+
+impl Readiness {
+    fn readable(token: Token) -> Self {
+        Readiness {
+            token,
+            readable: true,
+            writable: false,
+            read_closed: false,
+            write_closed: false,
+            priority: false,
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.readable |= other.readable;
+        self.writable |= other.writable;
+        self.read_closed |= other.read_closed;
+        self.write_closed |= other.write_closed;
+        self.priority |= other.priority;
+    }
+}
+
+impl<'a> From<&'a Event> for Readiness {
+    fn from(event: &'a Event) -> Self {
+        Readiness {
+            token: event.token(),
+            readable: event.is_readable(),
+            writable: event.is_writable(),
+            read_closed: event.is_read_closed(),
+            write_closed: event.is_write_closed(),
+            priority: event.is_priority(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,674 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    iter,
+    net::{SocketAddr, TcpListener},
+    time::Duration,
+};
+use socket2::{Socket, Domain, Type};
+
+use crate::{
+    proposal::{Proposal, ProposalKind, ConnectionOrigin, ConnectionId},
+    managed_stream::{TcpReadOnce, TcpWriteOnce},
+    proposer_builder::ProposerBuilder,
+    request::{Request, Backoff, ConnectionSource},
+    state::State,
+    time::TimeTracker,
+};
+use super::{Proposer, DuplicateConnect};
+
+type Kind = ProposalKind<(), (), ()>;
+
+type Io = ProposalKind<TcpReadOnce, TcpWriteOnce, ()>;
+
+/// Records the proposals, except the wake, the idle and the io, answers them with the script.
+struct Script<F> {
+    proposals: Vec<Kind>,
+    respond: F,
+}
+
+impl<F> State<TcpReadOnce, TcpWriteOnce> for Script<F>
+where
+    F: FnMut(Io) -> Request,
+{
+    type Ext = ();
+
+    type Rng = ();
+
+    fn accept(&mut self, proposal: Proposal<TcpReadOnce, TcpWriteOnce, (), ()>) -> Request {
+        let kind = proposal.kind.without_io();
+        let request = (self.respond)(proposal.kind);
+        match kind {
+            ProposalKind::Wake
+            | ProposalKind::OnReadable(..)
+            | ProposalKind::OnWritable(..)
+            | ProposalKind::Ready { .. }
+            | ProposalKind::Idle { .. } => (),
+            kind => self.proposals.push(kind),
+        }
+        request
+    }
+}
+
+type Tracker<F> = TimeTracker<iter::Repeat<()>, Script<F>, TcpReadOnce, TcpWriteOnce>;
+
+fn script<F>(respond: F) -> Tracker<F>
+where
+    F: FnMut(Io) -> Request,
+{
+    TimeTracker::new(
+        iter::repeat(()),
+        Script {
+            proposals: Vec::new(),
+            respond,
+        },
+    )
+}
+
+fn run<F>(proposer: &mut Proposer, tracker: &mut Tracker<F>, runs: usize) -> Vec<Kind>
+where
+    F: FnMut(Io) -> Request,
+{
+    for _ in 0..runs {
+        proposer.run(tracker, Duration::from_millis(10)).unwrap();
+    }
+    tracker.as_mut().proposals.drain(..).collect()
+}
+
+fn listener() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
+
+// nobody listens there
+fn closed_addr() -> SocketAddr {
+    listener().1
+}
+
+#[test]
+fn connect_host_tries_next_address() {
+    let (_listener, open) = listener();
+    let closed = closed_addr();
+
+    let mut proposer = Proposer::new(0, 8);
+    proposer
+        .resolver
+        .resolved("host".to_string(), 0, vec![closed, open]);
+    let mut tracker = script(|_| Request::default());
+    let proposals = run(&mut proposer, &mut tracker, 10);
+    assert!(
+        matches!(proposals[..], [Kind::Connection { addr, .. }] if addr == open),
+        "{:?}",
+        proposals
+    );
+}
+
+#[test]
+fn connect_host_every_address_fails() {
+    let closed = [closed_addr(), closed_addr()];
+
+    let mut proposer = Proposer::new(0, 8);
+    proposer
+        .resolver
+        .resolved("host".to_string(), 0, closed.to_vec());
+    let mut tracker = script(|_| Request::default());
+    let proposals = run(&mut proposer, &mut tracker, 10);
+    assert!(
+        matches!(proposals[..], [Kind::ConnectFailed { addr, .. }] if addr == closed[0]),
+        "{:?}",
+        proposals
+    );
+}
+
+fn shuffled_connects(seed: u64, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut proposer = ProposerBuilder::new()
+        .shuffle_connects(true)
+        .seed(seed)
+        .build()
+        .unwrap();
+    let mut tracker = script(|kind| match kind {
+        Io::Wake => addrs
+            .iter()
+            .fold(Request::default(), |r, addr| r.add_connect(*addr)),
+        _ => Request::default(),
+    });
+    run(&mut proposer, &mut tracker, 2)
+        .into_iter()
+        .filter_map(|kind| match kind {
+            Kind::Connection { addr, .. } => Some(addr),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn same_seed_same_connect_order() {
+    let listeners = (0..8).map(|_| listener()).collect::<Vec<_>>();
+    let addrs = listeners.iter().map(|(_, addr)| *addr).collect::<Vec<_>>();
+
+    let order = shuffled_connects(7, &addrs);
+    assert_eq!(order.len(), addrs.len());
+    assert_ne!(order, addrs);
+    assert_eq!(shuffled_connects(7, &addrs), order);
+}
+
+fn backoff_delays(seed: u64, addr: SocketAddr) -> Vec<Duration> {
+    let mut proposer = ProposerBuilder::new().seed(seed).build().unwrap();
+    let backoff = Backoff {
+        initial: Duration::from_millis(2),
+        max: Duration::from_millis(8),
+        multiplier: 2,
+        jitter: true,
+    };
+    let mut tracker = script(|kind| match kind {
+        Io::Wake => Request::default().add_persistent_connect(addr, backoff),
+        _ => Request::default(),
+    });
+    let mut delays = Vec::new();
+    while delays.len() < 4 {
+        let proposals = run(&mut proposer, &mut tracker, 1);
+        delays.extend(proposals.into_iter().filter_map(|kind| match kind {
+            Kind::ConnectFailed { retry_in, .. } => Some(retry_in),
+            _ => None,
+        }));
+    }
+    delays.truncate(4);
+    delays
+}
+
+#[test]
+fn same_seed_same_backoff() {
+    let addr = closed_addr();
+
+    let delays = backoff_delays(7, addr);
+    assert_eq!(backoff_delays(7, addr), delays);
+    // the jitter gives between the half of the delay and the delay
+    for (delay, attempt) in delays.iter().zip([2, 4, 8, 8]) {
+        let max = Duration::from_millis(attempt);
+        assert!(*delay >= max / 2 && *delay <= max, "{:?}", delays);
+    }
+}
+
+#[test]
+fn frame_too_long() {
+    use crate::framed::LengthPrefix;
+
+    let (listener, addr) = listener();
+    let mut proposer = Proposer::new(0, 8);
+    let mut tracker = script(|kind| match kind {
+        Io::Wake => Request::default().add_connect(addr),
+        Io::Connection { id, .. } => Request::default().send_frame_with_prefix(
+            id,
+            LengthPrefix::U16BigEndian,
+            vec![0; 0x10000],
+        ),
+        _ => Request::default(),
+    });
+    let proposals = run(&mut proposer, &mut tracker, 4);
+    assert!(
+        matches!(
+            proposals[..],
+            [
+                Kind::Connection { .. },
+                Kind::WriteRejected {
+                    length: 0x10000,
+                    ..
+                }
+            ]
+        ),
+        "{:?}",
+        proposals
+    );
+    drop(listener);
+}
+
+#[test]
+fn incoming_flag() {
+    let mut proposer = Proposer::new(0, 8);
+    let mut tracker = script(|kind| match kind {
+        Io::Wake => Request::default().set_source(ConnectionSource::Port(0)),
+        // connect to itself
+        Io::Listening { addr } => {
+            Request::default().add_connect(SocketAddr::from(([127, 0, 0, 1], addr.port())))
+        },
+        _ => Request::default(),
+    });
+    let mut proposals = run(&mut proposer, &mut tracker, 4).into_iter();
+    let listening = match proposals.next() {
+        Some(Kind::Listening { addr }) => addr,
+        p => panic!("{:?}", p),
+    };
+    let (outgoing, incoming) = match (proposals.next(), proposals.next()) {
+        (
+            Some(Kind::Connection {
+                addr,
+                incoming: false,
+                origin,
+                ..
+            }),
+            Some(Kind::Connection {
+                incoming: true,
+                origin: accepted,
+                ..
+            }),
+        ) => ((addr, origin), accepted),
+        p => panic!("{:?}", p),
+    };
+    assert_eq!(outgoing.0.port(), listening.port());
+    assert_eq!(outgoing.1, ConnectionOrigin::Outgoing);
+    assert_eq!(incoming, ConnectionOrigin::Listener(listening));
+    assert!(proposals.next().is_none());
+}
+
+// the connection to it stays in progress, the queue of the listener is full
+fn saturated_listener() -> (Socket, SocketAddr, std::net::TcpStream) {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    socket
+        .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+        .unwrap();
+    socket.listen(0).unwrap();
+    let addr = socket.local_addr().unwrap().as_socket().unwrap();
+    let queued = std::net::TcpStream::connect(addr).unwrap();
+    (socket, addr, queued)
+}
+
+fn duplicate_connect(policy: DuplicateConnect, addr: SocketAddr) -> Vec<Kind> {
+    let mut proposer = ProposerBuilder::new()
+        .duplicate_connect(policy)
+        .build()
+        .unwrap();
+    // the second request comes while the first connection is in progress
+    let mut tracker = script(|kind| match kind {
+        Io::Wake => Request::default().add_connect(addr).add_connect(addr),
+        _ => Request::default(),
+    });
+    run(&mut proposer, &mut tracker, 4)
+}
+
+#[test]
+fn duplicate_connect_policy() {
+    let (_listener, addr, _queued) = saturated_listener();
+
+    let proposals = duplicate_connect(DuplicateConnect::Ignore, addr);
+    assert!(
+        matches!(
+            proposals[..],
+            [Kind::Connection { id, .. }, Kind::AlreadyConnected { id: same, .. }] if id == same
+        ),
+        "{:?}",
+        proposals
+    );
+
+    let proposals = duplicate_connect(DuplicateConnect::Coalesce, addr);
+    assert!(
+        matches!(proposals[..], [Kind::Connection { .. }]),
+        "{:?}",
+        proposals
+    );
+
+    // waits until the connection in progress is established or fails
+    let proposals = duplicate_connect(DuplicateConnect::Queue, addr);
+    assert!(
+        matches!(proposals[..], [Kind::Connection { .. }]),
+        "{:?}",
+        proposals
+    );
+
+    let proposals = duplicate_connect(DuplicateConnect::Allow, addr);
+    assert!(
+        matches!(
+            proposals[..],
+            [Kind::Connection { id, .. }, Kind::Connection { id: other, .. }] if id != other
+        ),
+        "{:?}",
+        proposals
+    );
+}
+
+#[test]
+fn duplicate_connect_queued() {
+    // established, the queued request is answered
+    let (_listener, addr) = listener();
+    let proposals = duplicate_connect(DuplicateConnect::Queue, addr);
+    assert!(
+        matches!(
+            proposals[..],
+            [Kind::Connection { id, .. }, Kind::AlreadyConnected { id: same, .. }] if id == same
+        ),
+        "{:?}",
+        proposals
+    );
+
+    // failed, the queued request connects again
+    let addr = closed_addr();
+    let proposals = duplicate_connect(DuplicateConnect::Queue, addr);
+    assert!(
+        matches!(
+            proposals[..],
+            [Kind::Connection { id, .. }, Kind::Connection { id: other, .. }] if id != other
+        ),
+        "{:?}",
+        proposals
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn urgent_byte() {
+    use std::{cell::RefCell, io::Write};
+    use socket2::SockRef;
+    use crate::proposal::{ReadOnce, IoResult};
+
+    let (listener, addr) = listener();
+    let mut proposer = ProposerBuilder::new().urgent_data(true).build().unwrap();
+    let urgent = RefCell::new(Vec::new());
+    let mut tracker = script(|kind| match kind {
+        Io::Wake => Request::default().add_connect(addr),
+        Io::OnPriority(_, _, reader) => {
+            let mut buf = [0; 4];
+            let result = reader.read(&mut buf);
+            if let IoResult::Done { length, .. } = result {
+                urgent.borrow_mut().extend_from_slice(&buf[..length]);
+            }
+            Request::default()
+        },
+        _ => Request::default(),
+    });
+    run(&mut proposer, &mut tracker, 2);
+    let (mut peer, _) = listener.accept().unwrap();
+    peer.write_all(b"regular").unwrap();
+    SockRef::from(&peer).send_out_of_band(b"!").unwrap();
+    run(&mut proposer, &mut tracker, 4);
+    drop(tracker);
+    assert_eq!(urgent.into_inner(), b"!");
+}
+
+#[test]
+fn error_accessors() {
+    let (_listener, addr) = listener();
+    let mut proposer = Proposer::new(0, 8);
+    let source = ConnectionSource::Port(addr.port());
+    let mut tracker = script(|kind| match kind {
+        Io::Wake => Request::default().set_source(source),
+        _ => Request::default(),
+    });
+    proposer
+        .run(&mut tracker, Duration::from_millis(10))
+        .unwrap();
+    let error = proposer
+        .run(&mut tracker, Duration::from_millis(10))
+        .unwrap_err();
+    let (failed, io_error) = error.listen_error().unwrap();
+    assert!(matches!(failed, ConnectionSource::Port(port) if *port == addr.port()));
+    assert_eq!(io_error.kind(), std::io::ErrorKind::AddrInUse);
+    assert!(error.connect_errors().is_empty());
+    assert!(error.accept_error().is_none());
+    assert!(!error.rng_exhausted());
+    assert!(error.to_string().starts_with("failed to listen"));
+
+    // the error is taken, the next run is fine
+    proposer
+        .run(&mut tracker, Duration::from_millis(10))
+        .unwrap();
+}
+
+#[test]
+fn rng_exhausted() {
+    let mut proposer = Proposer::new(0, 8);
+    let script = Script {
+        proposals: Vec::new(),
+        respond: |_| Request::default(),
+    };
+    // the single random value is given to the wake
+    let mut tracker = TimeTracker::<_, _, TcpReadOnce, TcpWriteOnce>::new(iter::once(()), script);
+    let mut exhausted = false;
+    for _ in 0..4 {
+        if let Err(error) = proposer.run(&mut tracker, Duration::from_millis(10)) {
+            assert!(error.rng_exhausted());
+            assert!(error.listen_error().is_none());
+            exhausted = true;
+            break;
+        }
+    }
+    assert!(exhausted);
+}
+
+#[test]
+fn accept_cap_counts_dropped() {
+    let mut proposer = ProposerBuilder::new()
+        .report_rejected(true)
+        .build()
+        .unwrap();
+    let mut tracker = script(|kind| match kind {
+        Io::Wake => Request::default()
+            .set_source(ConnectionSource::Port(0))
+            .load_blacklist([[127, 0, 0, 1].into()]),
+        _ => Request::default(),
+    });
+    let port = match run(&mut proposer, &mut tracker, 2)[..] {
+        [Kind::Listening { addr }] => addr.port(),
+        ref p => panic!("{:?}", p),
+    };
+    let blacklisted = (0..100)
+        .map(|_| std::net::TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port))).unwrap())
+        .collect::<Vec<_>>();
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    socket
+        .bind(&SocketAddr::from(([127, 0, 0, 2], 0)).into())
+        .unwrap();
+    socket
+        .connect(&SocketAddr::from(([127, 0, 0, 1], port)).into())
+        .unwrap();
+
+    // the flood of dropped connections does not hold the proposer in a single event
+    let proposals = run(&mut proposer, &mut tracker, 1);
+    assert!(proposals.len() <= 64, "{}", proposals.len());
+
+    let mut proposals = proposals;
+    for _ in 0..10 {
+        proposals.extend(run(&mut proposer, &mut tracker, 1));
+    }
+    let rejected = proposals
+        .iter()
+        .filter(|kind| matches!(kind, Kind::Rejected { .. }))
+        .count();
+    assert_eq!(rejected, blacklisted.len());
+    // the peer which is not blacklisted is accepted, wherever it is in the backlog
+    let accepted = proposals
+        .iter()
+        .filter_map(|kind| match kind {
+            Kind::Connection { addr, .. } => Some(addr.ip()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(accepted, [std::net::IpAddr::from([127, 0, 0, 2])]);
+}
+
+// connects to the address, returns the id of the connection
+fn connected<F>(proposer: &mut Proposer, tracker: &mut Tracker<F>, addr: SocketAddr) -> ConnectionId
+where
+    F: FnMut(Io) -> Request,
+{
+    proposer.request += Request::default().add_connect(addr);
+    match run(proposer, tracker, 2)[..] {
+        [Kind::Connection { id, .. }] => id,
+        ref p => panic!("{:?}", p),
+    }
+}
+
+// closes the connection and waits until its token is recycled
+fn disconnected<F>(proposer: &mut Proposer, tracker: &mut Tracker<F>, id: ConnectionId)
+where
+    F: FnMut(Io) -> Request,
+{
+    proposer.request += Request::default().disconnect_id(id);
+    let proposals = run(proposer, tracker, 4);
+    assert!(
+        matches!(proposals[..], [Kind::Disconnected { id: closed, .. }] if closed == id),
+        "{:?}",
+        proposals
+    );
+}
+
+#[test]
+fn reconnect_stale_id() {
+    let (_a, a) = listener();
+    let (_b, b) = listener();
+
+    let mut proposer = Proposer::new(0, 8);
+    proposer.set_report_disconnects(true);
+    let mut tracker = script(|_| Request::default());
+    let id = connected(&mut proposer, &mut tracker, a);
+    disconnected(&mut proposer, &mut tracker, id);
+    // the token is given to the connection with another address
+    assert_eq!(connected(&mut proposer, &mut tracker, b), id);
+
+    proposer.request += Request::default().reconnect(id, a);
+    let proposals = run(&mut proposer, &mut tracker, 2);
+    assert!(
+        matches!(proposals[..], [Kind::ConnectFailed { addr, .. }] if addr == a),
+        "{:?}",
+        proposals
+    );
+    assert_eq!(proposer.addr_of(id), Some(b));
+}
+
+#[test]
+fn reconnect_reserved_id() {
+    let (_a, a) = listener();
+    let (_b, b) = listener();
+    let (_c, c) = listener();
+
+    let mut proposer = Proposer::new(0, 8);
+    proposer.set_report_disconnects(true);
+    let mut tracker = script(|_| Request::default());
+    let id = connected(&mut proposer, &mut tracker, a);
+    proposer.request += Request::default().reserve_id(id);
+    disconnected(&mut proposer, &mut tracker, id);
+    assert_ne!(connected(&mut proposer, &mut tracker, b), id);
+
+    proposer.request += Request::default().reconnect(id, a);
+    let proposals = run(&mut proposer, &mut tracker, 2);
+    assert!(
+        matches!(proposals[..], [Kind::Connection { id: same, addr, .. }] if same == id && addr == a),
+        "{:?}",
+        proposals
+    );
+
+    // the id is given to another connection once released
+    proposer.request += Request::default().release_id(id);
+    disconnected(&mut proposer, &mut tracker, id);
+    assert_eq!(connected(&mut proposer, &mut tracker, c), id);
+}
+
+#[test]
+fn reconnect_unknown_id() {
+    let (_a, a) = listener();
+
+    let mut proposer = Proposer::new(0, 8);
+    let mut tracker = script(|_| Request::default());
+    let id = ConnectionId {
+        poll_id: 0,
+        token: 1000,
+    };
+    proposer.request += Request::default().reconnect(id, a);
+    let proposals = run(&mut proposer, &mut tracker, 2);
+    assert!(
+        matches!(proposals[..], [Kind::ConnectFailed { addr, .. }] if addr == a),
+        "{:?}",
+        proposals
+    );
+}
+
+#[test]
+fn retained_event_after_shutdown_read() {
+    use std::io::Write;
+
+    let (a_listener, a) = listener();
+    let (b_listener, b) = listener();
+
+    let mut proposer = Proposer::new(0, 8);
+    let mut ids = Vec::new();
+    let mut tracker = script(move |kind| match kind {
+        Io::Connection { id, .. } => {
+            ids.push(id);
+            Request::default()
+        },
+        // the event of the other connection is retained, its read half is shut down meanwhile
+        Io::OnReadable(id, ..) => ids
+            .iter()
+            .filter(|other| **other != id)
+            .fold(Request::default(), |r, other| r.shutdown_read(*other)),
+        _ => Request::default(),
+    });
+    proposer.request += Request::default().add_connect(a).add_connect(b);
+    let proposals = run(&mut proposer, &mut tracker, 2);
+    assert_eq!(proposals.len(), 2, "{:?}", proposals);
+    let mut streams = [a_listener, b_listener].map(|l| l.accept().unwrap().0);
+
+    proposer.set_max_proposals_per_run(Some(1));
+    for stream in &mut streams {
+        stream.write_all(b"data").unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(10));
+    let proposals = run(&mut proposer, &mut tracker, 4);
+    assert!(proposals.is_empty(), "{:?}", proposals);
+}
+
+#[test]
+fn close_where_skips_persistent_connect() {
+    let (_listener, addr, _queued) = saturated_listener();
+
+    let mut proposer = Proposer::new(0, 8);
+    let mut tracker = script(|_| Request::default());
+    proposer.request += Request::default().add_persistent_connect(addr, Backoff::default());
+    let proposals = run(&mut proposer, &mut tracker, 2);
+    assert!(proposals.is_empty(), "{:?}", proposals);
+
+    // the connection in progress belongs to the persistent connect, not to the state
+    assert_eq!(proposer.close_where(|_, _| true).unwrap(), 0);
+    assert!(proposer.stream_registry.token_of(&addr).is_some());
+}
+
+#[test]
+fn write_after_shutdown_rejected() {
+    let (_listener, addr) = listener();
+
+    let mut proposer = Proposer::new(0, 8);
+    let mut tracker = script(|_| Request::default());
+    let id = connected(&mut proposer, &mut tracker, addr);
+    proposer.request += Request::default()
+        .shutdown_write(id)
+        .queue_write(id, b"data".to_vec());
+    let proposals = run(&mut proposer, &mut tracker, 2);
+    assert!(
+        matches!(proposals[..], [Kind::WriteRejected { id: rejected, length: 4 }] if rejected == id),
+        "{:?}",
+        proposals
+    );
+}
+
+#[test]
+fn wake_now_not_timed_out() {
+    use std::{rc::Rc, cell::RefCell};
+
+    let idle = Rc::new(RefCell::new(Vec::new()));
+    let mut proposer = Proposer::new(0, 8);
+    let mut tracker = script({
+        let idle = idle.clone();
+        move |kind| match kind {
+            Io::Wake => Request::default().wake_now(),
+            Io::Idle { timed_out } => {
+                idle.borrow_mut().push(timed_out);
+                Request::default()
+            },
+            _ => Request::default(),
+        }
+    });
+    run(&mut proposer, &mut tracker, 3);
+    // the first run only wakes the state, the second poll returns at once,
+    // the third one waits for the timeout
+    assert_eq!(*idle.borrow(), [false, true]);
+}
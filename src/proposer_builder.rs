@@ -0,0 +1,235 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::io;
+
+use super::proposer::{Proposer, Fairness, IdlePolicy, Trigger, DuplicateConnect, WriteOverflow};
+
+/// Configures the proposer before it is created.
+#[derive(Debug, Clone)]
+pub struct ProposerBuilder {
+    id: u16,
+    events_capacity: usize,
+    max_connections: Option<usize>,
+    per_ip_limit_outgoing: bool,
+    max_proposals_per_run: Option<usize>,
+    fairness: Fairness,
+    idle_policy: IdlePolicy,
+    raw_event_order: bool,
+    close_on_drop: bool,
+    trigger: Trigger,
+    shuffle_connects: bool,
+    seed: Option<u64>,
+    coalesce_ready: bool,
+    report_disconnects: bool,
+    report_rejected: bool,
+    blacklist_outgoing: bool,
+    duplicate_connect: DuplicateConnect,
+    write_overflow: WriteOverflow,
+    sequential_ids: bool,
+    #[cfg(unix)]
+    urgent_data: bool,
+}
+
+impl Default for ProposerBuilder {
+    fn default() -> Self {
+        ProposerBuilder {
+            id: 0,
+            events_capacity: 256,
+            max_connections: None,
+            per_ip_limit_outgoing: false,
+            max_proposals_per_run: None,
+            fairness: Fairness::default(),
+            idle_policy: IdlePolicy::default(),
+            raw_event_order: false,
+            close_on_drop: false,
+            trigger: Trigger::default(),
+            shuffle_connects: false,
+            seed: None,
+            coalesce_ready: false,
+            report_disconnects: false,
+            report_rejected: false,
+            blacklist_outgoing: true,
+            duplicate_connect: DuplicateConnect::default(),
+            write_overflow: WriteOverflow::default(),
+            sequential_ids: false,
+            #[cfg(unix)]
+            urgent_data: false,
+        }
+    }
+}
+
+impl ProposerBuilder {
+    pub fn new() -> Self {
+        ProposerBuilder::default()
+    }
+
+    /// The id is the part of every `ConnectionId` of the proposer.
+    pub fn id(self, id: u16) -> Self {
+        let mut s = self;
+        s.id = id;
+        s
+    }
+
+    /// How many events a single poll can report.
+    pub fn events_capacity(self, events_capacity: usize) -> Self {
+        let mut s = self;
+        s.events_capacity = events_capacity;
+        s
+    }
+
+    /// The proposer drops incoming connections and refuses to connect
+    /// if it has this many connections.
+    pub fn max_connections(self, max: usize) -> Self {
+        let mut s = self;
+        s.max_connections = Some(max);
+        s
+    }
+
+    /// The outgoing connections count toward the per ip limit set by the `Request`.
+    pub fn per_ip_limit_outgoing(self, outgoing: bool) -> Self {
+        let mut s = self;
+        s.per_ip_limit_outgoing = outgoing;
+        s
+    }
+
+    pub fn max_proposals_per_run(self, max: usize) -> Self {
+        let mut s = self;
+        s.max_proposals_per_run = Some(max);
+        s
+    }
+
+    pub fn fairness(self, fairness: Fairness) -> Self {
+        let mut s = self;
+        s.fairness = fairness;
+        s
+    }
+
+    pub fn idle_policy(self, idle_policy: IdlePolicy) -> Self {
+        let mut s = self;
+        s.idle_policy = idle_policy;
+        s
+    }
+
+    pub fn raw_event_order(self, raw: bool) -> Self {
+        let mut s = self;
+        s.raw_event_order = raw;
+        s
+    }
+
+    pub fn close_on_drop(self, close_on_drop: bool) -> Self {
+        let mut s = self;
+        s.close_on_drop = close_on_drop;
+        s
+    }
+
+    pub fn level_triggered(self, level_triggered: bool) -> Self {
+        let mut s = self;
+        s.trigger = if level_triggered {
+            Trigger::Level
+        } else {
+            Trigger::Edge
+        };
+        s
+    }
+
+    /// See `Trigger` for the portability caveats.
+    pub fn trigger(self, trigger: Trigger) -> Self {
+        let mut s = self;
+        s.trigger = trigger;
+        s
+    }
+
+    pub fn shuffle_connects(self, shuffle: bool) -> Self {
+        let mut s = self;
+        s.shuffle_connects = shuffle;
+        s
+    }
+
+    /// The seed of the proposer's random decisions, the id by default.
+    pub fn seed(self, seed: u64) -> Self {
+        let mut s = self;
+        s.seed = Some(seed);
+        s
+    }
+
+    /// Propose `Ready` rather than separate `OnReadable` and `OnWritable`.
+    pub fn coalesce_ready(self, coalesce: bool) -> Self {
+        let mut s = self;
+        s.coalesce_ready = coalesce;
+        s
+    }
+
+    pub fn report_disconnects(self, report: bool) -> Self {
+        let mut s = self;
+        s.report_disconnects = report;
+        s
+    }
+
+    pub fn report_rejected(self, report: bool) -> Self {
+        let mut s = self;
+        s.report_rejected = report;
+        s
+    }
+
+    /// Do not connect to the blacklisted peers, enabled by default.
+    pub fn blacklist_outgoing(self, outgoing: bool) -> Self {
+        let mut s = self;
+        s.blacklist_outgoing = outgoing;
+        s
+    }
+
+    pub fn duplicate_connect(self, policy: DuplicateConnect) -> Self {
+        let mut s = self;
+        s.duplicate_connect = policy;
+        s
+    }
+
+    pub fn write_overflow(self, policy: WriteOverflow) -> Self {
+        let mut s = self;
+        s.write_overflow = policy;
+        s
+    }
+
+    /// The ids of the connections are sequential, for the reproducible runs.
+    pub fn sequential_ids(self, sequential: bool) -> Self {
+        let mut s = self;
+        s.sequential_ids = sequential;
+        s
+    }
+
+    /// Propose `OnPriority` when the peer sent the urgent byte.
+    #[cfg(unix)]
+    pub fn urgent_data(self, urgent: bool) -> Self {
+        let mut s = self;
+        s.urgent_data = urgent;
+        s
+    }
+
+    /// Fails if the os cannot provide the poll.
+    pub fn build(self) -> io::Result<Proposer> {
+        let mut proposer = Proposer::with_capacity(self.id, self.events_capacity)?;
+        proposer.set_max_connections(self.max_connections);
+        proposer.set_per_ip_limit_outgoing(self.per_ip_limit_outgoing);
+        proposer.set_max_proposals_per_run(self.max_proposals_per_run);
+        proposer.set_fairness(self.fairness);
+        proposer.set_idle_policy(self.idle_policy);
+        proposer.set_raw_event_order(self.raw_event_order);
+        proposer.set_close_on_drop(self.close_on_drop);
+        proposer.set_trigger(self.trigger);
+        proposer.set_shuffle_connects(self.shuffle_connects);
+        if let Some(seed) = self.seed {
+            proposer.set_seed(seed);
+        }
+        proposer.set_coalesce_ready(self.coalesce_ready);
+        proposer.set_report_disconnects(self.report_disconnects);
+        proposer.set_report_rejected(self.report_rejected);
+        proposer.set_blacklist_outgoing(self.blacklist_outgoing);
+        proposer.set_duplicate_connect(self.duplicate_connect);
+        proposer.set_write_overflow(self.write_overflow);
+        proposer.set_sequential_ids(self.sequential_ids);
+        #[cfg(unix)]
+        proposer.set_urgent_data(self.urgent_data);
+        Ok(proposer)
+    }
+}
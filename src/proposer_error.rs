@@ -6,13 +6,18 @@ use smallvec::SmallVec;
 
 use super::request::ConnectionSource;
 
+/// The errors of the single run, the proposer keeps running after any of them.
+/// The fields are private, read them with the accessors.
 #[derive(Debug, Default)]
 pub struct ProposerError {
-    pub listen_error: Option<(ConnectionSource, io::Error)>,
-    pub connect_errors: SmallVec<[(SocketAddr, io::Error); 8]>,
-    pub disconnect_errors: SmallVec<[(SocketAddr, io::Error); 4]>,
-    pub accept_error: Option<io::Error>,
-    pub poll_error: Option<io::Error>,
+    pub(crate) listen_error: Option<(ConnectionSource, io::Error)>,
+    pub(crate) connect_errors: SmallVec<[(SocketAddr, io::Error); 8]>,
+    pub(crate) disconnect_errors: SmallVec<[(SocketAddr, io::Error); 4]>,
+    pub(crate) socket_errors: SmallVec<[(SocketAddr, io::Error); 4]>,
+    pub(crate) registration_errors: SmallVec<[(Option<SocketAddr>, io::Error); 4]>,
+    pub(crate) accept_error: Option<io::Error>,
+    pub(crate) poll_error: Option<io::Error>,
+    pub(crate) rng_exhausted: bool,
 }
 
 impl fmt::Display for ProposerError {
@@ -26,12 +31,28 @@ impl fmt::Display for ProposerError {
         for (addr, error) in &self.disconnect_errors {
             write!(f, "failed to disconnect from: {}, error: {}", addr, error)?;
         }
+        for (addr, error) in &self.socket_errors {
+            write!(f, "failed to set socket option: {}, error: {}", addr, error)?;
+        }
+        for (addr, error) in &self.registration_errors {
+            match addr {
+                Some(addr) => write!(f, "failed to register: {}, error: {}", addr, error)?,
+                None => write!(
+                    f,
+                    "failed to register the listener or the waker, error: {}",
+                    error
+                )?,
+            }
+        }
         if let Some(error) = &self.accept_error {
             write!(f, "failed to accept a connection, error: {}", error)?;
         }
         if let Some(error) = &self.poll_error {
             write!(f, "failed to poll the events, error: {}", error)?;
         }
+        if self.rng_exhausted {
+            write!(f, "the random values are exhausted")?;
+        }
 
         Ok(())
     }
@@ -40,6 +61,46 @@ impl fmt::Display for ProposerError {
 impl Error for ProposerError {}
 
 impl ProposerError {
+    /// The requested source could not be set, the proposer does not listen.
+    pub fn listen_error(&self) -> Option<(&ConnectionSource, &io::Error)> {
+        self.listen_error
+            .as_ref()
+            .map(|(source, error)| (source, error))
+    }
+
+    pub fn connect_errors(&self) -> &[(SocketAddr, io::Error)] {
+        &self.connect_errors
+    }
+
+    pub fn disconnect_errors(&self) -> &[(SocketAddr, io::Error)] {
+        &self.disconnect_errors
+    }
+
+    pub fn socket_errors(&self) -> &[(SocketAddr, io::Error)] {
+        &self.socket_errors
+    }
+
+    /// Registering the socket in the poll failed, e.g. the os is out of resources.
+    /// The address of the peer, or `None` for the listener and the waker.
+    /// The connection is dropped, the listener stops accepting until the next source.
+    pub fn registration_errors(&self) -> &[(Option<SocketAddr>, io::Error)] {
+        &self.registration_errors
+    }
+
+    pub fn accept_error(&self) -> Option<&io::Error> {
+        self.accept_error.as_ref()
+    }
+
+    pub fn poll_error(&self) -> Option<&io::Error> {
+        self.poll_error.as_ref()
+    }
+
+    /// The iterator of the random values ran out, the state receives no more proposals.
+    pub fn rng_exhausted(&self) -> bool {
+        self.rng_exhausted
+    }
+
+    #[allow(clippy::result_large_err)]
     pub(super) fn take_result(&mut self) -> Result<(), Self> {
         use std::mem;
 
@@ -50,8 +111,11 @@ impl ProposerError {
                 listen_error: self.listen_error.take(),
                 connect_errors: mem::take(&mut self.connect_errors),
                 disconnect_errors: mem::take(&mut self.disconnect_errors),
+                socket_errors: mem::take(&mut self.socket_errors),
+                registration_errors: mem::take(&mut self.registration_errors),
                 accept_error: self.accept_error.take(),
                 poll_error: self.poll_error.take(),
+                rng_exhausted: mem::take(&mut self.rng_exhausted),
             })
         }
     }
@@ -60,7 +124,10 @@ impl ProposerError {
         self.listen_error.is_none()
             && self.connect_errors.is_empty()
             && self.disconnect_errors.is_empty()
+            && self.socket_errors.is_empty()
+            && self.registration_errors.is_empty()
             && self.accept_error.is_none()
             && self.poll_error.is_none()
+            && !self.rng_exhausted
     }
 }
@@ -0,0 +1,34 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+/// The counters of the proposer since it is created or since the last reset.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProposerMetrics {
+    pub connections_accepted: u64,
+    pub connect_attempts: u64,
+    pub connect_failures: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Not a counter, the number of connections at the moment.
+    pub live_connections: u64,
+    pub poll_iterations: u64,
+    /// The polls which returned no events, the timeout elapsed.
+    pub poll_timeouts: u64,
+    /// The events returned by all polls.
+    pub polled_events: u64,
+    /// The polls which filled the events buffer, the rest of the events waits
+    /// for the next poll. Raise `events_capacity` if it grows often.
+    pub saturated_polls: u64,
+}
+
+impl ProposerMetrics {
+    /// The average number of events returned by the poll.
+    pub fn events_per_poll(&self) -> f64 {
+        if self.poll_iterations == 0 {
+            0.0
+        } else {
+            self.polled_events as f64 / self.poll_iterations as f64
+        }
+    }
+}
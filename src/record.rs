@@ -0,0 +1,273 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc, time::Duration};
+
+use super::{
+    proposal::{Proposal, ProposalKind, ReadOnce, WriteOnce, IoResult},
+    request::Request,
+    state::State,
+};
+
+/// The single entry of the recorded log.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Record<Ext, Rng> {
+    /// The proposal as the state received it, without the io objects.
+    Proposal {
+        rng: Rng,
+        elapsed: Duration,
+        // absent in the logs recorded before it was introduced
+        #[cfg_attr(feature = "serde", serde(default))]
+        since_start: Duration,
+        kind: ProposalKind<(), (), Ext>,
+    },
+    /// The io performed by the state while it was accepting the preceding proposal.
+    Io(RecordedIo),
+}
+
+/// The bytes actually read or written and the result of the operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecordedIo {
+    pub data: Vec<u8>,
+    pub result: IoResult,
+}
+
+/// The sink of the recorded log.
+pub trait ProposalRecorder<Ext, Rng> {
+    fn record(&mut self, record: Record<Ext, Rng>);
+}
+
+impl<Ext, Rng> ProposalRecorder<Ext, Rng> for Vec<Record<Ext, Rng>> {
+    fn record(&mut self, record: Record<Ext, Rng>) {
+        self.push(record);
+    }
+}
+
+type IoLog = Rc<RefCell<Vec<RecordedIo>>>;
+
+fn done_length(result: &IoResult) -> usize {
+    match result {
+        IoResult::Done { length, .. } => *length,
+        IoResult::Closed | IoResult::WouldBlock | IoResult::Eof | IoResult::Error(_) => 0,
+    }
+}
+
+pub struct RecordingReadOnce<R> {
+    inner: R,
+    log: IoLog,
+}
+
+impl<R> ReadOnce for RecordingReadOnce<R>
+where
+    R: ReadOnce,
+{
+    fn read(self, buf: &mut [u8]) -> IoResult {
+        let result = self.inner.read(buf);
+        let data = buf[..done_length(&result)].to_vec();
+        self.log.borrow_mut().push(RecordedIo { data, result });
+        result
+    }
+
+    fn read_and_keep(self, buf: &mut [u8]) -> IoResult {
+        let result = self.inner.read_and_keep(buf);
+        let data = buf[..done_length(&result)].to_vec();
+        self.log.borrow_mut().push(RecordedIo { data, result });
+        result
+    }
+
+    fn defer(self) {
+        self.inner.defer()
+    }
+
+    fn close(self) {
+        self.inner.close()
+    }
+}
+
+pub struct RecordingWriteOnce<W> {
+    inner: W,
+    log: IoLog,
+}
+
+impl<W> WriteOnce for RecordingWriteOnce<W>
+where
+    W: WriteOnce,
+{
+    fn write(self, data: &[u8]) -> IoResult {
+        let result = self.inner.write(data);
+        let data = data[..done_length(&result)].to_vec();
+        self.log.borrow_mut().push(RecordedIo { data, result });
+        result
+    }
+
+    fn defer(self) {
+        self.inner.defer()
+    }
+
+    fn close(self) {
+        self.inner.close()
+    }
+}
+
+/// Wraps the state and records every proposal it accepts and every io it performs.
+/// The io performed outside of `accept` is recorded after the next proposal,
+/// such io breaks the replay.
+pub struct Recording<S, Rec> {
+    state: S,
+    recorder: Rec,
+    io: IoLog,
+}
+
+impl<S, Rec> Recording<S, Rec> {
+    pub fn new(state: S, recorder: Rec) -> Self {
+        Recording {
+            state,
+            recorder,
+            io: Rc::default(),
+        }
+    }
+
+    pub fn recorder(&self) -> &Rec {
+        &self.recorder
+    }
+
+    pub fn into_inner(self) -> (S, Rec) {
+        (self.state, self.recorder)
+    }
+}
+
+impl<S, Rec> AsMut<S> for Recording<S, Rec> {
+    fn as_mut(&mut self) -> &mut S {
+        &mut self.state
+    }
+}
+
+impl<S, Rec> AsRef<S> for Recording<S, Rec> {
+    fn as_ref(&self) -> &S {
+        &self.state
+    }
+}
+
+impl<S, Rec, R, W> State<R, W> for Recording<S, Rec>
+where
+    R: ReadOnce,
+    W: WriteOnce,
+    S: State<RecordingReadOnce<R>, RecordingWriteOnce<W>>,
+    S::Ext: Clone,
+    S::Rng: Clone,
+    Rec: ProposalRecorder<S::Ext, S::Rng>,
+{
+    type Ext = S::Ext;
+
+    type Rng = S::Rng;
+
+    fn accept(&mut self, proposal: Proposal<R, W, Self::Ext, Self::Rng>) -> Request {
+        let Proposal {
+            rng,
+            elapsed,
+            since_start,
+            kind,
+        } = proposal;
+        self.recorder.record(Record::Proposal {
+            rng: rng.clone(),
+            elapsed,
+            since_start,
+            kind: kind.without_io(),
+        });
+
+        let io = &self.io;
+        let kind = kind.map_io(
+            |inner| RecordingReadOnce {
+                inner,
+                log: io.clone(),
+            },
+            |inner| RecordingWriteOnce {
+                inner,
+                log: io.clone(),
+            },
+        );
+        let request = self.state.accept(Proposal {
+            rng,
+            elapsed,
+            since_start,
+            kind,
+        });
+
+        for io in self.io.borrow_mut().drain(..) {
+            self.recorder.record(Record::Io(io));
+        }
+        request
+    }
+}
+
+type IoQueue = Rc<RefCell<VecDeque<RecordedIo>>>;
+
+/// Returns the recorded bytes and result, `IoResult::Closed` if the log has no more io.
+pub struct ReplayReadOnce(IoQueue);
+
+impl ReadOnce for ReplayReadOnce {
+    fn read(self, buf: &mut [u8]) -> IoResult {
+        match self.0.borrow_mut().pop_front() {
+            Some(RecordedIo { data, result }) => {
+                let length = data.len().min(buf.len());
+                buf[..length].clone_from_slice(&data[..length]);
+                result
+            },
+            None => IoResult::Closed,
+        }
+    }
+}
+
+/// Returns the recorded result, `IoResult::Closed` if the log has no more io.
+pub struct ReplayWriteOnce(IoQueue);
+
+impl WriteOnce for ReplayWriteOnce {
+    fn write(self, _data: &[u8]) -> IoResult {
+        match self.0.borrow_mut().pop_front() {
+            Some(RecordedIo { result, .. }) => result,
+            None => IoResult::Closed,
+        }
+    }
+}
+
+/// Feed the recorded log into the state, returns the requests the state issued.
+pub fn replay<S, I>(state: &mut S, log: I) -> Vec<Request>
+where
+    S: State<ReplayReadOnce, ReplayWriteOnce>,
+    I: IntoIterator<Item = Record<S::Ext, S::Rng>>,
+{
+    let io = IoQueue::default();
+    let mut requests = Vec::new();
+    let mut log = log.into_iter().peekable();
+    while let Some(record) = log.next() {
+        match record {
+            Record::Proposal {
+                rng,
+                elapsed,
+                since_start,
+                kind,
+            } => {
+                // the io recorded after the proposal is performed while accepting it
+                while let Some(Record::Io(_)) = log.peek() {
+                    if let Some(Record::Io(recorded)) = log.next() {
+                        io.borrow_mut().push_back(recorded);
+                    }
+                }
+                let kind = kind.map_io(
+                    |()| ReplayReadOnce(io.clone()),
+                    |()| ReplayWriteOnce(io.clone()),
+                );
+                requests.push(state.accept(Proposal {
+                    rng,
+                    elapsed,
+                    since_start,
+                    kind,
+                }));
+            },
+            Record::Io(recorded) => io.borrow_mut().push_back(recorded),
+        }
+    }
+    requests
+}
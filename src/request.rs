@@ -1,16 +1,59 @@
 // Copyright 2021 Vladislav Melnik
 // SPDX-License-Identifier: MIT
 
-use std::{net::SocketAddr, mem, ops::AddAssign, fmt};
+use std::{
+    net::{SocketAddr, IpAddr, Shutdown},
+    mem,
+    ops::AddAssign,
+    fmt,
+    time::Duration,
+};
 use smallvec::SmallVec;
 
+use super::{proposal::ConnectionId, framed::LengthPrefix};
+
 /// The proposer will perform requests sequentially.
-/// First it setup source, then blacklists and then connect.
+/// First it setup source and limits, then reserves and releases the ids, then disconnects, resets, shutdowns and pauses,
+/// then blacklists, then queues the writes, then blacklists after flush,
+/// then connect and then socket options.
+/// The timers are taken by the `TimeTracker` as soon as the state returns the request.
 #[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Request {
     source: Option<ConnectionSource>,
+    disconnect: SmallVec<[SocketAddr; 4]>,
+    disconnect_id: SmallVec<[ConnectionId; 4]>,
+    reset: SmallVec<[ConnectionId; 4]>,
+    shutdown_write: SmallVec<[ConnectionId; 4]>,
+    shutdown_read: SmallVec<[ConnectionId; 4]>,
+    pause_read: SmallVec<[(ConnectionId, bool); 4]>,
+    pause_write: SmallVec<[(ConnectionId, bool); 4]>,
+    arm_write: SmallVec<[ConnectionId; 4]>,
     blacklist: SmallVec<[SocketAddr; 4]>,
+    blacklist_id: SmallVec<[ConnectionId; 4]>,
+    blacklist_ip: SmallVec<[IpAddr; 4]>,
+    blacklist_after_flush: SmallVec<[(SocketAddr, Vec<u8>); 1]>,
     connect: SmallVec<[SocketAddr; 8]>,
+    connect_from: SmallVec<[(SocketAddr, SocketAddr); 4]>,
+    reconnect: SmallVec<[(ConnectionId, SocketAddr); 2]>,
+    reserve_id: SmallVec<[ConnectionId; 2]>,
+    release_id: SmallVec<[ConnectionId; 2]>,
+    connect_race: SmallVec<[Vec<SocketAddr>; 1]>,
+    connect_host: SmallVec<[(String, u16); 2]>,
+    connect_socks5: SmallVec<[(SocketAddr, SocketAddr); 2]>,
+    persistent_connect: SmallVec<[(SocketAddr, Backoff); 4]>,
+    cancel_persistent_connect: SmallVec<[SocketAddr; 4]>,
+    socket_options: SmallVec<[(ConnectionId, SocketOption); 4]>,
+    queued_writes: SmallVec<[QueuedWrite; 4]>,
+    // the frames too long for their prefix, the proposer rejects them
+    rejected_frames: SmallVec<[(ConnectionId, usize); 1]>,
+    write_buffer_limit: SmallVec<[(ConnectionId, usize); 2]>,
+    // `None` cancels the timer
+    timers: SmallVec<[(u64, Option<Duration>); 2]>,
+    per_ip_limit: Option<u32>,
+    accept_rate: Option<(u32, u32)>,
+    wake_now: bool,
+    wake: bool,
 }
 
 impl Request {
@@ -20,6 +63,83 @@ impl Request {
         s
     }
 
+    pub fn add_disconnect<A>(self, addr: A) -> Self
+    where
+        A: Into<SocketAddr>,
+    {
+        let mut s = self;
+        s.disconnect.push(addr.into());
+        s
+    }
+
+    pub fn disconnect_id(self, id: ConnectionId) -> Self {
+        let mut s = self;
+        s.disconnect_id.push(id);
+        s
+    }
+
+    /// Close the connection abruptly with RST rather than FIN,
+    /// the data not yet sent is discarded.
+    pub fn reset(self, id: ConnectionId) -> Self {
+        let mut s = self;
+        s.reset.push(id);
+        s
+    }
+
+    /// Send FIN, the peer will read the end of the stream, the read half keeps working.
+    /// The data queued with `queue_write` is written first.
+    pub fn shutdown_write(self, id: ConnectionId) -> Self {
+        let mut s = self;
+        s.shutdown_write.push(id);
+        s
+    }
+
+    /// Stop reading, the write half keeps working.
+    pub fn shutdown_read(self, id: ConnectionId) -> Self {
+        let mut s = self;
+        s.shutdown_read.push(id);
+        s
+    }
+
+    /// Stop reading from the connection, the os buffers the incoming data
+    /// until the buffer is full, then the peer cannot send more. The writes are not affected.
+    pub fn pause_read(self, id: ConnectionId) -> Self {
+        let mut s = self;
+        s.pause_read.push((id, true));
+        s
+    }
+
+    /// The state receives `OnReadable` again, if there is the data.
+    pub fn resume_read(self, id: ConnectionId) -> Self {
+        let mut s = self;
+        s.pause_read.push((id, false));
+        s
+    }
+
+    /// Stop offering `WriteOnce`, the state is not told the connection is writable.
+    /// The data queued with `queue_write` is still written, and `WriteDrained` proposed.
+    pub fn pause_write(self, id: ConnectionId) -> Self {
+        let mut s = self;
+        s.pause_write.push((id, true));
+        s
+    }
+
+    /// The state receives `OnWritable` again.
+    pub fn resume_write(self, id: ConnectionId) -> Self {
+        let mut s = self;
+        s.pause_write.push((id, false));
+        s
+    }
+
+    /// The accepted connection waits only for the data from the peer,
+    /// ask to wait for writability too, so the state receives `OnWritable` after the next poll,
+    /// e.g. to send the greeting first.
+    pub fn arm_write(self, id: ConnectionId) -> Self {
+        let mut s = self;
+        s.arm_write.push(id);
+        s
+    }
+
     pub fn add_to_blacklist<A>(self, addr: A) -> Self
     where
         A: Into<SocketAddr>,
@@ -38,6 +158,37 @@ impl Request {
         s
     }
 
+    /// Blacklist the peer as `add_to_blacklist` does, but tell it why before closing.
+    /// The goodbye is written after the data queued already, even by this request,
+    /// then the connection is closed, the data queued later is dropped.
+    /// The state receives no more reads and writes of the connection meanwhile.
+    pub fn blacklist_after_flush<A, D>(self, addr: A, goodbye: D) -> Self
+    where
+        A: Into<SocketAddr>,
+        D: Into<Vec<u8>>,
+    {
+        let mut s = self;
+        s.blacklist_after_flush.push((addr.into(), goodbye.into()));
+        s
+    }
+
+    /// Blacklist the ip address of the remote peer of the connection.
+    pub fn blacklist_id(self, id: ConnectionId) -> Self {
+        let mut s = self;
+        s.blacklist_id.push(id);
+        s
+    }
+
+    /// Blacklist the ip addresses, for example the ban list saved before restart.
+    pub fn load_blacklist<I>(self, ips: I) -> Self
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        let mut s = self;
+        s.blacklist_ip.extend(ips);
+        s
+    }
+
     pub fn add_connect<A>(self, addr: A) -> Self
     where
         A: Into<SocketAddr>,
@@ -56,46 +207,604 @@ impl Request {
         s
     }
 
+    /// Connect to the `remote` peer from the given `local` address.
+    pub fn add_connect_from<L, A>(self, local: L, remote: A) -> Self
+    where
+        L: Into<SocketAddr>,
+        A: Into<SocketAddr>,
+    {
+        let mut s = self;
+        s.connect_from.push((local.into(), remote.into()));
+        s
+    }
+
+    /// Connect to every address of the peer at once, e.g. both ipv4 and ipv6,
+    /// the first established connection wins, the rest are closed. The state receives
+    /// the single `Connection` for the winner, or `ConnectFailed` with the smallest address
+    /// if every attempt failed. The smallest address wins if several are established at once.
+    pub fn add_connect_race<I>(self, addrs: I) -> Self
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        let mut s = self;
+        s.connect_race.push(addrs.into_iter().collect());
+        s
+    }
+
+    /// Connect to the peer with the id of the previous connection, so the state keeps
+    /// its per-connection data. The live connection with the id is closed without `Disconnected`.
+    /// The id of the closed connection is kept for one run after `Disconnected`,
+    /// request it right away, later the id might be given to another connection,
+    /// or keep it with `reserve_id`.
+    /// The state receives `Connection` with the same id, or `ConnectFailed` if the id is unknown
+    /// or belongs to the connection with another address, that connection stays.
+    pub fn reconnect<A>(self, id: ConnectionId, addr: A) -> Self
+    where
+        A: Into<SocketAddr>,
+    {
+        let mut s = self;
+        s.reconnect.push((id, addr.into()));
+        s
+    }
+
+    /// Keep the id after the connection is closed, it is not given to another connection,
+    /// so `reconnect` may reuse it any time later. The id stays reserved across the reconnects
+    /// until `release_id`.
+    pub fn reserve_id(self, id: ConnectionId) -> Self {
+        let mut s = self;
+        s.reserve_id.push(id);
+        s
+    }
+
+    /// The reserved id is given to another connection as usual, once its connection is closed.
+    pub fn release_id(self, id: ConnectionId) -> Self {
+        let mut s = self;
+        s.release_id.push(id);
+        s
+    }
+
+    /// Connect to the `target` through the SOCKS5 `proxy`, without authentication.
+    /// The state receives `Connection` with the target address once the tunnel is established,
+    /// or `ConnectFailed` if the proxy refused.
+    pub fn add_connect_via_socks5<P, A>(self, proxy: P, target: A) -> Self
+    where
+        P: Into<SocketAddr>,
+        A: Into<SocketAddr>,
+    {
+        let mut s = self;
+        s.connect_socks5.push((proxy.into(), target.into()));
+        s
+    }
+
+    /// Resolve the host name and connect to the first address which accepts the connection.
+    /// The name is resolved in a background thread, the addresses are tried one by one,
+    /// the state receives `Connection` when one accepts, `ConnectFailed` with the first address
+    /// if none does, or `ResolveFailed` in some later proposal.
+    pub fn add_connect_host<H>(self, host: H, port: u16) -> Self
+    where
+        H: Into<String>,
+    {
+        let mut s = self;
+        s.connect_host.push((host.into(), port));
+        s
+    }
+
+    /// The proposer keeps connected to the peer, it reconnects with the backoff
+    /// if the connection fails or is lost.
+    pub fn add_persistent_connect<A>(self, addr: A, backoff: Backoff) -> Self
+    where
+        A: Into<SocketAddr>,
+    {
+        let mut s = self;
+        s.persistent_connect.push((addr.into(), backoff));
+        s
+    }
+
+    /// Stop reconnecting, the established connection stays.
+    pub fn cancel_persistent_connect<A>(self, addr: A) -> Self
+    where
+        A: Into<SocketAddr>,
+    {
+        let mut s = self;
+        s.cancel_persistent_connect.push(addr.into());
+        s
+    }
+
+    /// Enable tcp keepalive with the given idle time, or disable it with `None`.
+    pub fn set_keepalive(self, id: ConnectionId, idle: Option<Duration>) -> Self {
+        let mut s = self;
+        s.socket_options.push((id, SocketOption::Keepalive(idle)));
+        s
+    }
+
+    /// Set the size of the receive buffer, the os might adjust it.
+    pub fn set_recv_buffer(self, id: ConnectionId, size: usize) -> Self {
+        let mut s = self;
+        s.socket_options.push((id, SocketOption::RecvBuffer(size)));
+        s
+    }
+
+    /// Set the size of the send buffer, the os might adjust it.
+    pub fn set_send_buffer(self, id: ConnectionId, size: usize) -> Self {
+        let mut s = self;
+        s.socket_options.push((id, SocketOption::SendBuffer(size)));
+        s
+    }
+
+    /// The state receives `Wake` again at the beginning of the next run,
+    /// e.g. to issue the initial requests after the reconfiguration.
+    pub fn request_wake(self) -> Self {
+        let mut s = self;
+        s.wake = true;
+        s
+    }
+
+    /// Don't wait for the timeout, the next poll returns immediately
+    /// and the state receives `Idle` with `timed_out` false if nothing happened.
+    pub fn wake_now(self) -> Self {
+        let mut s = self;
+        s.wake_now = true;
+        s
+    }
+
+    /// The state receives `Timer` with the key after the delay, at the earliest.
+    /// Setting the timer with the same key again replaces it.
+    pub fn set_timer(self, key: u64, after: Duration) -> Self {
+        let mut s = self;
+        s.timers.push((key, Some(after)));
+        s
+    }
+
+    /// The state does not receive `Timer` with the key, unless it sets it again.
+    pub fn cancel_timer(self, key: u64) -> Self {
+        let mut s = self;
+        s.timers.push((key, None));
+        s
+    }
+
+    /// The proposer writes the data to the connection as it becomes writable,
+    /// and proposes `WriteDrained` when the last queued byte is written.
+    /// Don't write to the connection directly until then, the data would interleave.
+    pub fn queue_write<D>(self, id: ConnectionId, data: D) -> Self
+    where
+        D: Into<Vec<u8>>,
+    {
+        let mut s = self;
+        s.queued_writes.push(QueuedWrite {
+            id,
+            data: data.into(),
+            frame: false,
+            now: false,
+        });
+        s
+    }
+
+    /// Write the data right away, e.g. in response to the timer, the rest is queued
+    /// and written as `queue_write` does. Nothing is written before the connection
+    /// is established, the data is queued then.
+    pub fn send<D>(self, id: ConnectionId, data: D) -> Self
+    where
+        D: Into<Vec<u8>>,
+    {
+        let mut s = self;
+        s.queued_writes.push(QueuedWrite {
+            id,
+            data: data.into(),
+            frame: false,
+            now: true,
+        });
+        s
+    }
+
+    /// Prepend the `u32` big endian length to the payload and queue it as `queue_write` does.
+    /// The frame is never interleaved with other data.
+    pub fn send_frame<D>(self, id: ConnectionId, payload: D) -> Self
+    where
+        D: Into<Vec<u8>>,
+    {
+        self.send_frame_with_prefix(id, LengthPrefix::default(), payload)
+    }
+
+    /// The payload which length does not fit the prefix is not sent,
+    /// the state receives `WriteRejected` with the length of the payload.
+    pub fn send_frame_with_prefix<D>(
+        self,
+        id: ConnectionId,
+        prefix: LengthPrefix,
+        payload: D,
+    ) -> Self
+    where
+        D: Into<Vec<u8>>,
+    {
+        let payload = payload.into();
+        let mut data = match prefix.encode(payload.len()) {
+            Some(prefix) => prefix.to_vec(),
+            None => {
+                log::warn!(
+                    "the frame is too long: {}, prefix: {:?}",
+                    payload.len(),
+                    prefix
+                );
+                let mut s = self;
+                s.rejected_frames.push((id, payload.len()));
+                return s;
+            },
+        };
+        data.extend_from_slice(&payload);
+        let mut s = self;
+        s.queued_writes.push(QueuedWrite {
+            id,
+            data,
+            frame: true,
+            now: false,
+        });
+        s
+    }
+
+    /// Limit the data queued with `queue_write` and `send_frame` and not yet written,
+    /// the proposer rejects the data or disconnects the peer if the limit would be exceeded.
+    /// The data queued already is not affected. It applies to the writes of this request too.
+    pub fn set_write_buffer_limit(self, id: ConnectionId, limit: usize) -> Self {
+        let mut s = self;
+        s.write_buffer_limit.push((id, limit));
+        s
+    }
+
+    /// Drop incoming connections from the ip which already has this many connections.
+    pub fn set_per_ip_limit(self, limit: u32) -> Self {
+        let mut s = self;
+        s.per_ip_limit = Some(limit);
+        s
+    }
+
+    /// Accept at most `per_sec` connections per second on average, and `burst` at once.
+    /// The proposer stops accepting when the rate is exceeded, the connections wait
+    /// in the backlog of the os. The zero `burst` is the same as one,
+    /// the bucket which holds nothing would never accept.
+    pub fn set_accept_rate(self, per_sec: u32, burst: u32) -> Self {
+        let mut s = self;
+        s.accept_rate = Some((per_sec, burst));
+        s
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.source.is_none() && self.blacklist.is_empty() && self.connect.is_empty()
+        self.source.is_none()
+            && self.disconnect.is_empty()
+            && self.disconnect_id.is_empty()
+            && self.reset.is_empty()
+            && self.shutdown_write.is_empty()
+            && self.shutdown_read.is_empty()
+            && self.pause_read.is_empty()
+            && self.pause_write.is_empty()
+            && self.arm_write.is_empty()
+            && self.blacklist.is_empty()
+            && self.blacklist_id.is_empty()
+            && self.blacklist_ip.is_empty()
+            && self.blacklist_after_flush.is_empty()
+            && self.connect.is_empty()
+            && self.connect_from.is_empty()
+            && self.reconnect.is_empty()
+            && self.reserve_id.is_empty()
+            && self.release_id.is_empty()
+            && self.connect_race.is_empty()
+            && self.connect_host.is_empty()
+            && self.connect_socks5.is_empty()
+            && self.persistent_connect.is_empty()
+            && self.cancel_persistent_connect.is_empty()
+            && self.socket_options.is_empty()
+            && self.queued_writes.is_empty()
+            && self.rejected_frames.is_empty()
+            && self.write_buffer_limit.is_empty()
+            && self.timers.is_empty()
+            && self.per_ip_limit.is_none()
+            && self.accept_rate.is_none()
+            && !self.wake_now
+            && !self.wake
     }
 
     pub fn take_new_source(&mut self) -> Option<ConnectionSource> {
         self.source.take()
     }
 
+    pub fn take_disconnects(&mut self) -> impl Iterator<Item = SocketAddr> {
+        mem::take(&mut self.disconnect).into_iter()
+    }
+
+    pub fn take_disconnect_ids(&mut self) -> impl Iterator<Item = ConnectionId> {
+        mem::take(&mut self.disconnect_id).into_iter()
+    }
+
+    pub fn take_resets(&mut self) -> impl Iterator<Item = ConnectionId> {
+        mem::take(&mut self.reset).into_iter()
+    }
+
+    pub fn take_shutdowns(&mut self) -> impl Iterator<Item = (ConnectionId, Shutdown)> {
+        let write = mem::take(&mut self.shutdown_write)
+            .into_iter()
+            .map(|id| (id, Shutdown::Write));
+        let read = mem::take(&mut self.shutdown_read)
+            .into_iter()
+            .map(|id| (id, Shutdown::Read));
+        write.chain(read)
+    }
+
+    /// The connection and whether to pause or to resume, in order.
+    pub fn take_read_pauses(&mut self) -> impl Iterator<Item = (ConnectionId, bool)> {
+        mem::take(&mut self.pause_read).into_iter()
+    }
+
+    /// The connection and whether to pause or to resume, in order.
+    pub fn take_write_pauses(&mut self) -> impl Iterator<Item = (ConnectionId, bool)> {
+        mem::take(&mut self.pause_write).into_iter()
+    }
+
+    pub fn take_arm_writes(&mut self) -> impl Iterator<Item = ConnectionId> {
+        mem::take(&mut self.arm_write).into_iter()
+    }
+
     pub fn take_blacklist(&mut self) -> impl Iterator<Item = SocketAddr> {
         mem::take(&mut self.blacklist).into_iter()
     }
 
+    pub fn take_blacklist_after_flush(&mut self) -> impl Iterator<Item = (SocketAddr, Vec<u8>)> {
+        mem::take(&mut self.blacklist_after_flush).into_iter()
+    }
+
+    pub fn take_blacklist_ids(&mut self) -> impl Iterator<Item = ConnectionId> {
+        mem::take(&mut self.blacklist_id).into_iter()
+    }
+
+    pub fn take_blacklist_ips(&mut self) -> impl Iterator<Item = IpAddr> {
+        mem::take(&mut self.blacklist_ip).into_iter()
+    }
+
     pub fn take_connects(&mut self) -> impl Iterator<Item = SocketAddr> {
         mem::take(&mut self.connect).into_iter()
     }
+
+    /// Pairs of the local and the remote address.
+    pub fn take_connects_from(&mut self) -> impl Iterator<Item = (SocketAddr, SocketAddr)> {
+        mem::take(&mut self.connect_from).into_iter()
+    }
+
+    pub fn take_connect_races(&mut self) -> impl Iterator<Item = Vec<SocketAddr>> {
+        mem::take(&mut self.connect_race).into_iter()
+    }
+
+    pub fn take_reconnects(&mut self) -> impl Iterator<Item = (ConnectionId, SocketAddr)> {
+        mem::take(&mut self.reconnect).into_iter()
+    }
+
+    pub fn take_reserved_ids(&mut self) -> impl Iterator<Item = ConnectionId> {
+        mem::take(&mut self.reserve_id).into_iter()
+    }
+
+    pub fn take_released_ids(&mut self) -> impl Iterator<Item = ConnectionId> {
+        mem::take(&mut self.release_id).into_iter()
+    }
+
+    pub fn take_connect_hosts(&mut self) -> impl Iterator<Item = (String, u16)> {
+        mem::take(&mut self.connect_host).into_iter()
+    }
+
+    pub fn take_connects_via_socks5(&mut self) -> impl Iterator<Item = (SocketAddr, SocketAddr)> {
+        mem::take(&mut self.connect_socks5).into_iter()
+    }
+
+    pub fn take_persistent_connects(&mut self) -> impl Iterator<Item = (SocketAddr, Backoff)> {
+        mem::take(&mut self.persistent_connect).into_iter()
+    }
+
+    pub fn take_persistent_cancels(&mut self) -> impl Iterator<Item = SocketAddr> {
+        mem::take(&mut self.cancel_persistent_connect).into_iter()
+    }
+
+    pub fn take_socket_options(&mut self) -> impl Iterator<Item = (ConnectionId, SocketOption)> {
+        mem::take(&mut self.socket_options).into_iter()
+    }
+
+    /// The connection, the data, whether the data is a frame, and whether to write it now.
+    pub fn take_queued_writes(
+        &mut self,
+    ) -> impl Iterator<Item = (ConnectionId, Vec<u8>, bool, bool)> {
+        mem::take(&mut self.queued_writes).into_iter().map(
+            |QueuedWrite {
+                 id,
+                 data,
+                 frame,
+                 now,
+             }| (id, data, frame, now),
+        )
+    }
+
+    /// The connection and the length of the payload which does not fit the prefix.
+    pub fn take_rejected_frames(&mut self) -> impl Iterator<Item = (ConnectionId, usize)> {
+        mem::take(&mut self.rejected_frames).into_iter()
+    }
+
+    pub fn take_write_buffer_limits(&mut self) -> impl Iterator<Item = (ConnectionId, usize)> {
+        mem::take(&mut self.write_buffer_limit).into_iter()
+    }
+
+    /// The key and the delay, or `None` if the timer is cancelled, in order of requests.
+    pub fn take_timers(&mut self) -> impl Iterator<Item = (u64, Option<Duration>)> {
+        mem::take(&mut self.timers).into_iter()
+    }
+
+    pub fn take_per_ip_limit(&mut self) -> Option<u32> {
+        self.per_ip_limit.take()
+    }
+
+    /// The rate per second and the burst.
+    pub fn take_accept_rate(&mut self) -> Option<(u32, u32)> {
+        self.accept_rate.take()
+    }
+
+    pub fn take_wake_now(&mut self) -> bool {
+        mem::take(&mut self.wake_now)
+    }
+
+    pub fn take_wake(&mut self) -> bool {
+        mem::take(&mut self.wake)
+    }
 }
 
 impl AddAssign<Request> for Request {
     fn add_assign(&mut self, rhs: Request) {
         let Request {
             source,
+            mut disconnect,
+            mut disconnect_id,
+            mut reset,
+            mut shutdown_write,
+            mut shutdown_read,
+            mut pause_read,
+            mut pause_write,
+            mut arm_write,
             mut blacklist,
+            mut blacklist_id,
+            mut blacklist_ip,
+            mut blacklist_after_flush,
             mut connect,
+            mut connect_from,
+            mut reconnect,
+            mut reserve_id,
+            mut release_id,
+            mut connect_race,
+            mut connect_host,
+            mut connect_socks5,
+            mut persistent_connect,
+            mut cancel_persistent_connect,
+            mut socket_options,
+            mut queued_writes,
+            mut rejected_frames,
+            mut write_buffer_limit,
+            mut timers,
+            per_ip_limit,
+            accept_rate,
+            wake_now,
+            wake,
         } = rhs;
         #[allow(clippy::suspicious_op_assign_impl)]
         if self.source.is_none() && source.is_some() {
             self.source = source;
         }
+        self.disconnect.append(&mut disconnect);
+        self.disconnect_id.append(&mut disconnect_id);
+        self.reset.append(&mut reset);
+        self.shutdown_write.append(&mut shutdown_write);
+        self.shutdown_read.append(&mut shutdown_read);
+        self.pause_read.append(&mut pause_read);
+        self.pause_write.append(&mut pause_write);
+        self.arm_write.append(&mut arm_write);
         self.blacklist.append(&mut blacklist);
+        self.blacklist_id.append(&mut blacklist_id);
+        self.blacklist_ip.append(&mut blacklist_ip);
+        self.blacklist_after_flush
+            .append(&mut blacklist_after_flush);
         self.connect.append(&mut connect);
+        self.connect_from.append(&mut connect_from);
+        self.reconnect.append(&mut reconnect);
+        self.reserve_id.append(&mut reserve_id);
+        self.release_id.append(&mut release_id);
+        self.connect_race.append(&mut connect_race);
+        self.connect_host.append(&mut connect_host);
+        self.connect_socks5.append(&mut connect_socks5);
+        self.persistent_connect.append(&mut persistent_connect);
+        self.cancel_persistent_connect
+            .append(&mut cancel_persistent_connect);
+        self.socket_options.append(&mut socket_options);
+        self.queued_writes.append(&mut queued_writes);
+        self.rejected_frames.append(&mut rejected_frames);
+        self.write_buffer_limit.append(&mut write_buffer_limit);
+        self.timers.append(&mut timers);
+        // the latest limit wins
+        if per_ip_limit.is_some() {
+            self.per_ip_limit = per_ip_limit;
+        }
+        if accept_rate.is_some() {
+            self.accept_rate = accept_rate;
+        }
+        self.wake_now |= wake_now;
+        self.wake |= wake;
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct QueuedWrite {
+    id: ConnectionId,
+    data: Vec<u8>,
+    frame: bool,
+    // write right away, do not wait for the event
+    now: bool,
+}
+
+/// The option of the socket of the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SocketOption {
+    /// The idle time before tcp keepalive probes, `None` disables keepalive.
+    Keepalive(Option<Duration>),
+    RecvBuffer(usize),
+    SendBuffer(usize),
+}
+
+/// How long to wait before the next attempt to connect.
+/// The delay starts at `initial` and is multiplied after each failure up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: u32,
+    /// Wait a random time between the half of the delay and the delay.
+    pub jitter: bool,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            multiplier: 2,
+            jitter: true,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before the attempt, without jitter.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let mut delay = self.initial;
+        for _ in 0..attempt {
+            if delay >= self.max {
+                break;
+            }
+            delay = delay.saturating_mul(self.multiplier);
+        }
+        delay.min(self.max)
     }
 }
 
 /// Choose how the proposer will listen incoming connections
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConnectionSource {
     /// No incoming connections allowed
     None,
     /// Listen at port
     Port(u16),
+    /// Listen at port shared with other proposers, the kernel balances the connections
+    /// between them, sets `SO_REUSEPORT`, unix only
+    ReusePort(u16),
+    /// Listen at port on both ipv6 and ipv4, the single socket `[::]:port` without `IPV6_V6ONLY`.
+    /// The ipv4 peers are reported with their ipv4 addresses, not the ipv4-mapped ipv6 ones,
+    /// so the blacklist and the per ip limit treat them the same as with `Port`.
+    DualStack(u16),
+    /// Listen at the first port of the range which is not in use, both ends are inclusive.
+    /// `Listening` reports the chosen port.
+    PortRange { start: u16, end: u16 },
 }
 
 impl fmt::Display for ConnectionSource {
@@ -103,6 +812,61 @@ impl fmt::Display for ConnectionSource {
         match self {
             ConnectionSource::None => write!(f, "none"),
             ConnectionSource::Port(port) => write!(f, "port({})", port),
+            ConnectionSource::ReusePort(port) => write!(f, "reuse_port({})", port),
+            ConnectionSource::DualStack(port) => write!(f, "dual_stack({})", port),
+            ConnectionSource::PortRange { start, end } => {
+                write!(f, "port_range({}..={})", start, end)
+            },
+        }
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::proposal::ConnectionId;
+    use super::Request;
+
+    #[test]
+    fn serde_keeps_order() {
+        let addrs = (1..=3)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect::<Vec<_>>();
+        let ids = (1..=3)
+            .map(|token| ConnectionId { poll_id: 0, token })
+            .collect::<Vec<_>>();
+        let mut request = Request::default();
+        for i in [2, 0, 1] {
+            request = request
+                .add_connect(addrs[i])
+                .queue_write(ids[i], vec![i as u8])
+                .add_disconnect(addrs[(i + 1) % 3])
+                .disconnect_id(ids[i]);
         }
+
+        let json = serde_json::to_string(&request).unwrap();
+        let mut request = serde_json::from_str::<Request>(&json).unwrap();
+        let order = [2, 0, 1];
+        assert_eq!(
+            request.take_connects().collect::<Vec<_>>(),
+            order.map(|i| addrs[i])
+        );
+        assert_eq!(
+            request
+                .take_queued_writes()
+                .map(|(id, data, ..)| (id, data))
+                .collect::<Vec<_>>(),
+            order.map(|i| (ids[i], vec![i as u8]))
+        );
+        assert_eq!(
+            request.take_disconnects().collect::<Vec<_>>(),
+            order.map(|i| addrs[(i + 1) % 3])
+        );
+        assert_eq!(
+            request.take_disconnect_ids().collect::<Vec<_>>(),
+            order.map(|i| ids[i])
+        );
+        assert!(request.is_empty());
     }
 }
@@ -0,0 +1,99 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+};
+use mio::Waker;
+
+type Resolved = (String, u16, io::Result<Vec<SocketAddr>>);
+
+type Job = (String, u16, Option<Arc<Waker>>);
+
+// the lookups in flight at once, the others wait for a free worker
+const WORKERS: usize = 4;
+
+/// Resolves the host names in background threads, the resolution blocks.
+/// The threads are spawned on demand, at most `WORKERS`, they live as long as the resolver.
+pub struct Resolver {
+    jobs: mpsc::Sender<Job>,
+    queue: Arc<Mutex<mpsc::Receiver<Job>>>,
+    workers: usize,
+    sender: mpsc::Sender<Resolved>,
+    receiver: mpsc::Receiver<Resolved>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        let (jobs, queue) = mpsc::channel();
+        let (sender, receiver) = mpsc::channel();
+        Resolver {
+            jobs,
+            queue: Arc::new(Mutex::new(queue)),
+            workers: 0,
+            sender,
+            receiver,
+        }
+    }
+
+    /// The waker interrupts the poll when the resolution is done,
+    /// without it the result is taken after the poll times out.
+    pub fn resolve(&mut self, host: String, port: u16, waker: Option<Arc<Waker>>) {
+        if self.workers < WORKERS {
+            match self.spawn_worker() {
+                Ok(()) => self.workers += 1,
+                // the running workers take the job
+                Err(e) if self.workers > 0 => log::warn!("failed to spawn the resolver: {}", e),
+                Err(e) => {
+                    log::error!("failed to spawn the resolver: {}", e);
+                    let _ = self.sender.send((host, port, Err(e)));
+                    return;
+                },
+            }
+        }
+        // the receiver lives as long as the resolver
+        let _ = self.jobs.send((host, port, waker));
+    }
+
+    fn spawn_worker(&self) -> io::Result<()> {
+        let queue = self.queue.clone();
+        let sender = self.sender.clone();
+        thread::Builder::new()
+            .name("cold-io-resolver".to_string())
+            .spawn(move || loop {
+                // the lock is held while waiting, the idle workers queue up on it
+                let job = match queue.lock() {
+                    Ok(queue) => queue.recv(),
+                    Err(_) => return,
+                };
+                // the resolver is dropped
+                let (host, port, waker) = match job {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                let result = (host.as_str(), port)
+                    .to_socket_addrs()
+                    .map(Iterator::collect);
+                // the proposer might be dropped already, nothing to do then
+                if sender.send((host, port, result)).is_err() {
+                    return;
+                }
+                if let Some(waker) = waker {
+                    let _ = waker.wake();
+                }
+            })
+            .map(drop)
+    }
+
+    #[cfg(test)]
+    pub fn resolved(&self, host: String, port: u16, addrs: Vec<SocketAddr>) {
+        self.sender.send((host, port, Ok(addrs))).unwrap();
+    }
+
+    pub fn try_recv(&self) -> Option<Resolved> {
+        self.receiver.try_recv().ok()
+    }
+}
@@ -0,0 +1,29 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+/// The endless reproducible sequence of random numbers, one per proposal,
+/// give it to the `TimeTracker` when the state has `Rng = u64`.
+/// The same seed yields the same sequence on every platform.
+#[derive(Debug, Clone)]
+pub struct SeededRngs {
+    inner: ChaCha8Rng,
+}
+
+impl SeededRngs {
+    pub fn new(seed: u64) -> Self {
+        SeededRngs {
+            inner: ChaCha8Rng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Iterator for SeededRngs {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.inner.next_u64())
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+//! The data shared between the proposer and the once handles.
+//! It is single threaded by default, with the `sync` feature
+//! it is guarded by a mutex, so the handles can be sent to another thread.
+
+#[cfg(not(feature = "sync"))]
+mod imp {
+    use std::{
+        rc::{Rc, Weak},
+        cell::{RefCell, RefMut},
+    };
+
+    pub type Guard<'a, T> = RefMut<'a, T>;
+
+    pub struct Shared<T>(Rc<RefCell<T>>);
+
+    pub struct WeakShared<T>(Weak<RefCell<T>>);
+
+    impl<T> Shared<T> {
+        pub fn new(value: T) -> Self {
+            Shared(Rc::new(RefCell::new(value)))
+        }
+
+        pub fn lock(&self) -> Guard<'_, T> {
+            self.0.borrow_mut()
+        }
+
+        pub fn downgrade(&self) -> WeakShared<T> {
+            WeakShared(Rc::downgrade(&self.0))
+        }
+    }
+
+    impl<T> Clone for Shared<T> {
+        fn clone(&self) -> Self {
+            Shared(self.0.clone())
+        }
+    }
+
+    impl<T> WeakShared<T> {
+        pub fn upgrade(&self) -> Option<Shared<T>> {
+            self.0.upgrade().map(Shared)
+        }
+    }
+}
+
+#[cfg(feature = "sync")]
+mod imp {
+    use std::sync::{Arc, Weak, Mutex, MutexGuard, PoisonError};
+
+    pub type Guard<'a, T> = MutexGuard<'a, T>;
+
+    pub struct Shared<T>(Arc<Mutex<T>>);
+
+    pub struct WeakShared<T>(Weak<Mutex<T>>);
+
+    impl<T> Shared<T> {
+        pub fn new(value: T) -> Self {
+            Shared(Arc::new(Mutex::new(value)))
+        }
+
+        pub fn lock(&self) -> Guard<'_, T> {
+            // the data is plain flags and counters, it is consistent even if
+            // some thread panicked while holding the lock
+            self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn downgrade(&self) -> WeakShared<T> {
+            WeakShared(Arc::downgrade(&self.0))
+        }
+    }
+
+    impl<T> Clone for Shared<T> {
+        fn clone(&self) -> Self {
+            Shared(self.0.clone())
+        }
+    }
+
+    impl<T> WeakShared<T> {
+        pub fn upgrade(&self) -> Option<Shared<T>> {
+            self.0.upgrade().map(Shared)
+        }
+    }
+}
+
+pub use self::imp::{Shared, WeakShared, Guard};
+
+impl<T> Default for Shared<T>
+where
+    T: Default,
+{
+    fn default() -> Self {
+        Shared::new(T::default())
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    io::{self, Read, Write},
+    net::SocketAddr,
+};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+enum Step {
+    Connecting,
+    Method,
+    Reply,
+}
+
+/// The handshake with the SOCKS5 proxy, it is performed before the state
+/// receives the connection, the state never sees the proxy.
+pub struct Socks5Handshake {
+    target: SocketAddr,
+    step: Step,
+    output: Vec<u8>,
+    input: Vec<u8>,
+}
+
+impl Socks5Handshake {
+    pub fn new(target: SocketAddr) -> Self {
+        Socks5Handshake {
+            target,
+            step: Step::Connecting,
+            output: Vec::new(),
+            input: Vec::new(),
+        }
+    }
+
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    pub fn is_connecting(&self) -> bool {
+        matches!(self.step, Step::Connecting)
+    }
+
+    /// The tcp connection with the proxy is established, greet it.
+    pub fn connected(&mut self) {
+        self.step = Step::Method;
+        self.output = vec![VERSION, 1, NO_AUTH];
+    }
+
+    /// Write and read as much as the stream allows, returns whether the tunnel is established.
+    pub fn advance<S>(&mut self, mut stream: S) -> io::Result<bool>
+    where
+        S: Read + Write,
+    {
+        match self.try_advance(&mut stream) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            result => result,
+        }
+    }
+
+    fn try_advance<S>(&mut self, stream: &mut S) -> io::Result<bool>
+    where
+        S: Read + Write,
+    {
+        self.flush(stream)?;
+        loop {
+            match self.step {
+                Step::Connecting => return Ok(false),
+                Step::Method => {
+                    self.fill(stream, 2)?;
+                    if self.input != [VERSION, NO_AUTH] {
+                        return Err(io::Error::other("proxy requires authentication"));
+                    }
+                    self.input.clear();
+                    self.step = Step::Reply;
+                    self.output = self.connect_request();
+                    self.flush(stream)?;
+                },
+                Step::Reply => {
+                    self.fill(stream, 5)?;
+                    if self.input[0] != VERSION {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "not a SOCKS5 proxy",
+                        ));
+                    }
+                    if self.input[1] != 0 {
+                        let message = format!("proxy refused, reply: {}", self.input[1]);
+                        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, message));
+                    }
+                    // the bound address, not needed, but must be read off the stream
+                    let length = match self.input[3] {
+                        IPV4 => 4,
+                        IPV6 => 16,
+                        DOMAIN => 1 + self.input[4] as usize,
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "unknown address type",
+                            ))
+                        },
+                    };
+                    self.fill(stream, 4 + length + 2)?;
+                    return Ok(true);
+                },
+            }
+        }
+    }
+
+    fn flush<S>(&mut self, stream: &mut S) -> io::Result<()>
+    where
+        S: Write,
+    {
+        while !self.output.is_empty() {
+            match stream.write(&self.output)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                written => drop(self.output.drain(..written)),
+            }
+        }
+        Ok(())
+    }
+
+    // read exactly, the bytes after the reply belong to the state
+    fn fill<S>(&mut self, stream: &mut S, length: usize) -> io::Result<()>
+    where
+        S: Read,
+    {
+        let mut buf = [0; 0x100];
+        while self.input.len() < length {
+            let missing = (length - self.input.len()).min(buf.len());
+            match stream.read(&mut buf[..missing])? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                read => self.input.extend_from_slice(&buf[..read]),
+            }
+        }
+        Ok(())
+    }
+
+    fn connect_request(&self) -> Vec<u8> {
+        let mut request = vec![VERSION, CONNECT, 0];
+        match self.target {
+            SocketAddr::V4(addr) => {
+                request.push(IPV4);
+                request.extend_from_slice(&addr.ip().octets());
+            },
+            SocketAddr::V6(addr) => {
+                request.push(IPV6);
+                request.extend_from_slice(&addr.ip().octets());
+            },
+        }
+        request.extend_from_slice(&self.target.port().to_be_bytes());
+        request
+    }
+}
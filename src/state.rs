@@ -1,14 +1,18 @@
 // Copyright 2021 Vladislav Melnik
 // SPDX-License-Identifier: MIT
 
+use std::net::SocketAddr;
+
 use super::{
-    proposal::{Proposal, ReadOnce, WriteOnce},
+    proposal::{Proposal, ProposalKind, ConnectionId, ReadOnce, WriteOnce},
     request::Request,
 };
 
 /// The deterministic state machine.
 /// It accepts proposals from network and issues requests.
 /// All business logic should be implemented inside.
+/// Either implement `accept` and match every proposal, or override
+/// only the hooks needed, the provided `accept` dispatches to them.
 pub trait State<R, W>
 where
     R: ReadOnce,
@@ -21,5 +25,58 @@ where
     type Rng;
 
     /// In order to preserve determinism, it should be the only input to the state machine.
-    fn accept(&mut self, proposal: Proposal<R, W, Self::Ext, Self::Rng>) -> Request;
+    /// The hooks do not see the random value and the elapsed time, override it if needed.
+    /// The proposals without a hook are ignored, the io objects are dropped.
+    fn accept(&mut self, proposal: Proposal<R, W, Self::Ext, Self::Rng>) -> Request {
+        match proposal.kind {
+            ProposalKind::Wake => self.on_wake(),
+            ProposalKind::Idle { timed_out } => self.on_idle(timed_out),
+            ProposalKind::Connection {
+                addr, incoming, id, ..
+            } => self.on_connection(id, addr, incoming),
+            ProposalKind::OnReadable(id, addr, r) => self.on_readable(id, addr, r),
+            ProposalKind::OnWritable(id, addr, w) => self.on_writable(id, addr, w),
+            ProposalKind::Timer(key) => self.on_timer(key),
+            ProposalKind::Custom(ext) => self.on_custom(ext),
+            _ => Request::default(),
+        }
+    }
+
+    fn on_wake(&mut self) -> Request {
+        Request::default()
+    }
+
+    fn on_idle(&mut self, timed_out: bool) -> Request {
+        let _ = timed_out;
+        Request::default()
+    }
+
+    fn on_connection(&mut self, id: ConnectionId, addr: SocketAddr, incoming: bool) -> Request {
+        let _ = (id, addr, incoming);
+        Request::default()
+    }
+
+    /// By default the reading is deferred, the proposer offers the reader again.
+    fn on_readable(&mut self, id: ConnectionId, addr: SocketAddr, once: R) -> Request {
+        let _ = (id, addr);
+        once.defer();
+        Request::default()
+    }
+
+    /// By default the writing is deferred, the proposer offers the writer again.
+    fn on_writable(&mut self, id: ConnectionId, addr: SocketAddr, once: W) -> Request {
+        let _ = (id, addr);
+        once.defer();
+        Request::default()
+    }
+
+    fn on_timer(&mut self, key: u64) -> Request {
+        let _ = key;
+        Request::default()
+    }
+
+    fn on_custom(&mut self, ext: Self::Ext) -> Request {
+        let _ = ext;
+        Request::default()
+    }
 }
@@ -2,147 +2,1111 @@
 // SPDX-License-Identifier: MIT
 
 use std::{
-    collections::{BTreeMap, BTreeSet},
-    net::{SocketAddr, IpAddr},
+    collections::{HashMap, BTreeSet},
+    net::{SocketAddr, IpAddr, Shutdown},
     io,
-    time::Duration,
+    time::{Duration, Instant},
+    sync::Arc,
 };
 use mio::{
-    Poll, Events, Token,
-    net::{TcpListener, TcpStream},
+    Poll, Events, Token, Waker,
+    net::{TcpListener, TcpStream, TcpSocket},
     Interest,
 };
+use smallvec::SmallVec;
 
-use super::{managed_stream::ManagedStream, request::ConnectionSource, proposer_error::ProposerError};
+use super::{
+    managed_stream::ManagedStream,
+    marked_stream::{StreamOptions, Dirty, Totals},
+    request::{ConnectionSource, SocketOption},
+    proposal::{ConnectionStats, ConnectionInterest, DisconnectReason, RejectReason},
+    proposer_error::ProposerError,
+    proposer_metrics::ProposerMetrics,
+    socks5::Socks5Handshake,
+    token_bucket::TokenBucket,
+};
+
+/// What the token of the event stands for, the reserved tokens are
+/// at the top of the range, so the connections never reach them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Listener,
+    Waker,
+    Connection,
+}
+
+// the connections with the remote ip, the outgoing ones count toward the limit only if configured
+#[derive(Default)]
+struct IpCount {
+    incoming: usize,
+    outgoing: usize,
+}
+
+/// The single connection taken from the backlog of the listener.
+pub enum Accepted {
+    New(SocketAddr, Token),
+    /// The blacklist or the limits dropped it.
+    Dropped,
+}
+
+pub enum Connected {
+    New(Token),
+    Existing(Token),
+}
 
 pub struct StreamRegistry {
     poll: Poll,
     error: ProposerError,
     listener: Option<TcpListener>,
-    streams: BTreeMap<SocketAddr, ManagedStream>,
-    in_progress: BTreeMap<Token, SocketAddr>,
+    // the source the listener is created from and its address, meaningless without the listener
+    source: ConnectionSource,
+    local_addr: Option<SocketAddr>,
+    // the listener is deregistered until the accept rate allows to accept
+    listener_paused: bool,
+    accept_rate: Option<TokenBucket>,
+    waker: Option<Arc<Waker>>,
+    // only point lookups, the order of reregistration is given by the sorted dirty tokens
+    streams: HashMap<Token, ManagedStream>,
+    // several connections might have the same remote address, in order of creation
+    addrs: HashMap<SocketAddr, SmallVec<[Token; 1]>>,
+    // updated along with the addresses, so the per ip limit is checked without a scan
+    ips: HashMap<IpAddr, IpCount>,
+    dirty: Dirty,
+    // the buffer swapped with the dirty tokens, so neither is reallocated every run
+    dirty_spare: Vec<Token>,
+    pending_readers: Vec<Token>,
     blacklist: BTreeSet<IpAddr>,
     last_token: Token,
+    wrapped: bool,
+    free_tokens: Vec<Token>,
+    // the tokens of the streams closed during this run and during the previous one,
+    // they are not reused yet, so the state can reconnect with the same id,
+    // every token passes through them before it is free
+    released: Vec<Token>,
+    cooling: Vec<Token>,
+    // the tokens the state keeps for the reconnect, closed or not,
+    // they skip the released ones until the state releases them
+    reserved: BTreeSet<Token>,
+    // the test makes the registration in the poll fail
+    #[cfg(test)]
+    fail_register: bool,
+    options: StreamOptions,
+    max_connections: Option<usize>,
+    per_ip_limit: Option<u32>,
+    per_ip_outgoing: bool,
+    blacklist_outgoing: bool,
+    metrics: ProposerMetrics,
+    totals: Totals,
+    report_rejected: bool,
+    // the incoming connections dropped since the last time the proposer took them
+    rejected: Vec<(SocketAddr, RejectReason)>,
+    // the connections closed since the last time the proposer took them
+    disconnected: Vec<(Token, SocketAddr, DisconnectReason, ConnectionStats)>,
 }
 
 impl StreamRegistry {
     pub const LISTENER: Token = Token(usize::MAX);
+    pub const WAKER: Token = Token(usize::MAX - 1);
 
-    pub fn new() -> Self {
-        StreamRegistry {
-            poll: Poll::new().expect("cannot use non-blocking io"),
+    pub fn new() -> io::Result<Self> {
+        Ok(StreamRegistry {
+            poll: Poll::new()?,
             error: ProposerError::default(),
             listener: None,
-            streams: BTreeMap::default(),
-            in_progress: BTreeMap::default(),
+            source: ConnectionSource::None,
+            local_addr: None,
+            listener_paused: false,
+            accept_rate: None,
+            waker: None,
+            streams: HashMap::default(),
+            addrs: HashMap::default(),
+            ips: HashMap::default(),
+            dirty: Dirty::default(),
+            dirty_spare: Vec::new(),
+            pending_readers: Vec::new(),
             blacklist: BTreeSet::default(),
             last_token: Token(0),
+            wrapped: false,
+            free_tokens: Vec::new(),
+            released: Vec::new(),
+            cooling: Vec::new(),
+            reserved: BTreeSet::default(),
+            #[cfg(test)]
+            fail_register: false,
+            options: StreamOptions::default(),
+            max_connections: None,
+            per_ip_limit: None,
+            per_ip_outgoing: false,
+            blacklist_outgoing: true,
+            metrics: ProposerMetrics::default(),
+            totals: Totals::default(),
+            report_rejected: false,
+            rejected: Vec::new(),
+            disconnected: Vec::new(),
+        })
+    }
+
+    pub fn set_max_connections(&mut self, max: Option<usize>) {
+        self.max_connections = max;
+    }
+
+    pub fn set_per_ip_limit(&mut self, limit: Option<u32>) {
+        self.per_ip_limit = limit;
+    }
+
+    pub fn set_per_ip_outgoing(&mut self, outgoing: bool) {
+        self.per_ip_outgoing = outgoing;
+    }
+
+    pub fn set_blacklist_outgoing(&mut self, outgoing: bool) {
+        self.blacklist_outgoing = outgoing;
+    }
+
+    fn refuses(&mut self, addr: SocketAddr) -> bool {
+        let refuses = self.blacklist_outgoing && self.blacklist.contains(&addr.ip());
+        if refuses {
+            log::info!("blacklisted, do not connect: {}", addr);
+            self.reject(addr, RejectReason::Blacklisted);
         }
+        refuses
+    }
+
+    pub fn set_report_rejected(&mut self, report: bool) {
+        self.report_rejected = report;
+    }
+
+    pub fn take_rejected(&mut self) -> impl Iterator<Item = (SocketAddr, RejectReason)> + '_ {
+        self.rejected.drain(..)
+    }
+
+    fn reject(&mut self, addr: SocketAddr, reason: RejectReason) {
+        if self.report_rejected {
+            self.rejected.push((addr, reason));
+        }
+    }
+
+    pub fn metrics(&self) -> ProposerMetrics {
+        let totals = *self.totals.lock();
+        ProposerMetrics {
+            bytes_read: totals.bytes_read,
+            bytes_written: totals.bytes_written,
+            live_connections: self.streams.values().filter(|s| !s.closed()).count() as u64,
+            ..self.metrics
+        }
+    }
+
+    pub fn reset_metrics(&mut self) {
+        self.metrics = ProposerMetrics::default();
+        *self.totals.lock() = Default::default();
+    }
+
+    /// The outgoing connection failed after it was initiated.
+    pub fn count_connect_failure(&mut self) {
+        self.metrics.connect_failures += 1;
+    }
+
+    fn full(&self) -> bool {
+        // the closed streams are not reaped until the next reregister
+        self.max_connections.is_some_and(|max| {
+            self.streams
+                .values()
+                .filter(|stream| !stream.closed())
+                .count()
+                >= max
+        })
+    }
+
+    /// The connections with the ip reached the limit. The outgoing connections
+    /// are counted, and limited, only if configured.
+    fn ip_full(&self, ip: IpAddr, incoming: bool) -> bool {
+        let limit = match self.per_ip_limit {
+            Some(limit) if incoming || self.per_ip_outgoing => limit as usize,
+            _ => return false,
+        };
+        let count = match self.ips.get(&ip) {
+            Some(count) if self.per_ip_outgoing => count.incoming + count.outgoing,
+            Some(count) => count.incoming,
+            None => 0,
+        };
+        count >= limit
     }
 
     fn allocate_token(&mut self) -> Token {
-        let t = self.last_token;
-        self.last_token = Token(self.last_token.0 + 1);
-        t
+        // reuse the tokens of closed streams, so the tokens stay small and dense
+        if let Some(t) = self.free_tokens.pop() {
+            return t;
+        }
+        loop {
+            let t = self.last_token;
+            self.last_token = Token(t.0.wrapping_add(1));
+            if self.last_token.0 == 0 {
+                self.wrapped = true;
+            }
+            if Self::classify(t) != TokenKind::Connection {
+                continue;
+            }
+            // after the wrap around the token might still be in use
+            if self.wrapped && (self.streams.contains_key(&t) || self.reserved.contains(&t)) {
+                continue;
+            }
+            break t;
+        }
+    }
+
+    /// The single place which tells the reserved tokens, they are never given to a stream.
+    pub fn classify(token: Token) -> TokenKind {
+        match token {
+            Self::LISTENER => TokenKind::Listener,
+            Self::WAKER => TokenKind::Waker,
+            _ => TokenKind::Connection,
+        }
+    }
+
+    // register/reregister/deregister fail if the os is out of resources, e.g. EMFILE,
+    // the error is reported, the socket is dropped, the proposer keeps running
+    fn registration_error(&mut self, addr: Option<SocketAddr>, error: io::Error) {
+        log::warn!("failed to register: {:?}, error: {}", addr, error);
+        self.error.registration_errors.push((addr, error));
     }
 
-    pub fn set_source(&mut self, source: ConnectionSource) {
+    /// Interrupts the poll from another thread.
+    pub fn waker(&mut self) -> Option<Arc<Waker>> {
+        if self.waker.is_none() {
+            match Waker::new(self.poll.registry(), Self::WAKER) {
+                Ok(waker) => self.waker = Some(Arc::new(waker)),
+                Err(e) => self.registration_error(None, e),
+            }
+        }
+        self.waker.clone()
+    }
+
+    fn drop_listener(&mut self) {
         if let Some(mut listener) = self.listener.take() {
-            // register/reregister/deregister can only fail in case of the bug
-            // here and further we should panic in such situation,
-            // rather then propagate the error
-            self.poll.registry().deregister(&mut listener).expect("bug");
+            if !self.listener_paused {
+                if let Err(e) = self.poll.registry().deregister(&mut listener) {
+                    self.registration_error(None, e);
+                }
+            }
+        }
+        self.listener_paused = false;
+    }
+
+    /// Listen on the listener bound and listening already, e.g. inherited from the parent.
+    /// Replaces the current source.
+    pub fn adopt_listener(&mut self, listener: std::net::TcpListener) -> io::Result<SocketAddr> {
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        self.drop_listener();
+        let mut listener = TcpListener::from_std(listener);
+        self.poll
+            .registry()
+            .register(&mut listener, Self::LISTENER, Interest::READABLE)?;
+        self.listener = Some(listener);
+        self.source = ConnectionSource::Port(addr.port());
+        self.local_addr = Some(addr);
+        Ok(addr)
+    }
+
+    fn bind(source: ConnectionSource) -> io::Result<TcpListener> {
+        match source {
+            ConnectionSource::None => Err(io::ErrorKind::InvalidInput.into()),
+            ConnectionSource::Port(port) => TcpListener::bind(([0, 0, 0, 0], port).into()),
+            #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+            ConnectionSource::ReusePort(port) => {
+                let socket = TcpSocket::new_v4()?;
+                socket.set_reuseaddr(true)?;
+                socket.set_reuseport(true)?;
+                socket.bind(([0, 0, 0, 0], port).into())?;
+                socket.listen(1024)
+            },
+            #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+            ConnectionSource::ReusePort(_) => Err(io::ErrorKind::Unsupported.into()),
+            ConnectionSource::DualStack(port) => {
+                use socket2::{Domain, Protocol, Socket, Type};
+
+                let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+                socket.set_only_v6(false)?;
+                #[cfg(unix)]
+                socket.set_reuse_address(true)?;
+                socket.bind(&SocketAddr::from(([0u16; 8], port)).into())?;
+                socket.listen(1024)?;
+                socket.set_nonblocking(true)?;
+                Ok(TcpListener::from_std(socket.into()))
+            },
+            ConnectionSource::PortRange { start, end } => {
+                if start > end {
+                    return Err(io::ErrorKind::InvalidInput.into());
+                }
+                for port in start..end {
+                    match TcpListener::bind(([0, 0, 0, 0], port).into()) {
+                        Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                        result => return result,
+                    }
+                }
+                TcpListener::bind(([0, 0, 0, 0], end).into())
+            },
         }
+    }
+
+    pub fn set_source(&mut self, source: ConnectionSource) -> Option<SocketAddr> {
+        self.drop_listener();
 
         match source {
-            ConnectionSource::None => (),
-            ConnectionSource::Port(port) => {
-                let mut listener = match TcpListener::bind(([0, 0, 0, 0], port).into()) {
+            ConnectionSource::None => None,
+            ConnectionSource::Port(_)
+            | ConnectionSource::ReusePort(_)
+            | ConnectionSource::DualStack(_)
+            | ConnectionSource::PortRange { .. } => {
+                let bound =
+                    Self::bind(source).and_then(|listener| Ok((listener.local_addr()?, listener)));
+                let (addr, mut listener) = match bound {
                     Ok(v) => v,
                     Err(e) => {
                         self.error.listen_error = Some((source, e));
-                        return;
+                        return None;
                     },
                 };
-                self.poll
-                    .registry()
-                    .register(&mut listener, Self::LISTENER, Interest::READABLE)
-                    .expect("bug");
+                let registered = self.poll.registry().register(
+                    &mut listener,
+                    Self::LISTENER,
+                    Interest::READABLE,
+                );
+                if let Err(e) = registered {
+                    self.registration_error(None, e);
+                    return None;
+                }
                 self.listener = Some(listener);
+                self.source = source;
+                self.local_addr = Some(addr);
+                Some(addr)
             },
         }
     }
 
+    pub fn set_accept_rate(&mut self, per_sec: u32, burst: u32, now: Instant) {
+        self.accept_rate = Some(TokenBucket::new(per_sec, burst, now));
+    }
+
+    /// Refill the accept rate bucket, resume accepting if it has a token.
+    /// Returns when to check again if accepting is still paused.
+    pub fn refill_accept_rate(&mut self, now: Instant) -> Option<Instant> {
+        let bucket = self.accept_rate.as_mut()?;
+        bucket.refill(now);
+        if !self.listener_paused {
+            return None;
+        }
+        if !bucket.available() {
+            return bucket.next_refill();
+        }
+        if let Some(listener) = &mut self.listener {
+            // the connections waiting in the backlog are reported once it is registered
+            let registered =
+                self.poll
+                    .registry()
+                    .register(listener, Self::LISTENER, Interest::READABLE);
+            if let Err(e) = registered {
+                // stays paused, the next run tries again
+                self.registration_error(None, e);
+                return None;
+            }
+        }
+        self.listener_paused = false;
+        None
+    }
+
+    pub fn options(&self) -> StreamOptions {
+        self.options
+    }
+
+    pub fn set_options(&mut self, options: StreamOptions) {
+        self.options = options;
+        for stream in self.streams.values() {
+            stream.set_options(options);
+        }
+    }
+
     pub fn blacklist_peer(&mut self, addr: SocketAddr) {
         self.blacklist.insert(addr.ip());
-        if let Some(stream) = self.streams.remove(&addr) {
-            self.poll
-                .registry()
-                .deregister(stream.borrow_mut().as_mut())
-                .expect("bug");
+        for token in self.tokens_of(&addr) {
+            self.disconnect_peer(token, DisconnectReason::Blacklisted);
+        }
+    }
+
+    /// Blacklist the ip of the peer, write the goodbye to every connection with
+    /// the address, and close them once it is written. The connection whose
+    /// queue cannot take the goodbye is disconnected right away.
+    pub fn blacklist_after_flush(&mut self, addr: SocketAddr, goodbye: &[u8]) {
+        self.blacklist.insert(addr.ip());
+        for token in self.tokens_of(&addr) {
+            let queued = self.streams.get(&token).is_some_and(|stream| {
+                stream.close_after_flush(goodbye, DisconnectReason::Blacklisted)
+            });
+            if !queued {
+                self.disconnect_peer(token, DisconnectReason::Blacklisted);
+            }
+        }
+    }
+
+    /// Blacklist the ip address, disconnect every peer at it.
+    pub fn blacklist_ip(&mut self, ip: IpAddr) {
+        self.blacklist.insert(ip);
+        let mut tokens = self
+            .addrs
+            .iter()
+            .filter(|(addr, _)| addr.ip() == ip)
+            .flat_map(|(_, tokens)| tokens.iter().cloned())
+            .collect::<Vec<_>>();
+        // the order of the map is random, the disconnects go in order of the tokens
+        tokens.sort();
+        for token in tokens {
+            self.disconnect_peer(token, DisconnectReason::Blacklisted);
+        }
+    }
+
+    /// The source of the listener, `None` if there is no listener, e.g. it failed.
+    pub fn current_source(&self) -> ConnectionSource {
+        match &self.listener {
+            Some(_) => self.source,
+            None => ConnectionSource::None,
+        }
+    }
+
+    pub fn listening_addr(&self) -> Option<SocketAddr> {
+        self.listener.as_ref().and(self.local_addr)
+    }
+
+    pub fn blacklisted_ips(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.blacklist.iter().cloned()
+    }
+
+    pub fn disconnect_peer(&mut self, token: Token, reason: DisconnectReason) {
+        if let Some(stream) = self.remove_peer(token) {
+            let addr = stream.addr();
+            self.disconnected
+                .push((token, addr, reason, stream.stats()));
             if let Err(e) = stream.discard() {
                 self.error.disconnect_errors.push((addr, e))
             }
         }
     }
 
+    /// Drop the outgoing connection the state did not receive yet, it is not reported.
+    pub fn abort_peer(&mut self, token: Token) {
+        if let Some(stream) = self.remove_peer(token) {
+            let addr = stream.addr();
+            if let Err(e) = stream.discard() {
+                self.error.disconnect_errors.push((addr, e))
+            }
+        }
+    }
+
+    /// Close the connection abruptly, the unsent data is discarded.
+    pub fn reset_peer(&mut self, token: Token) {
+        if let Some(stream) = self.remove_peer(token) {
+            let addr = stream.addr();
+            let reason = DisconnectReason::Requested;
+            self.disconnected
+                .push((token, addr, reason, stream.stats()));
+            if let Err(e) = stream.reset() {
+                self.error.disconnect_errors.push((addr, e))
+            }
+        }
+    }
+
+    pub fn shutdown_peer(&mut self, token: Token, how: Shutdown) {
+        if let Some(stream) = self.streams.get(&token) {
+            stream.shutdown(how);
+        }
+    }
+
+    pub fn pause_read(&mut self, token: Token, paused: bool) {
+        if let Some(stream) = self.streams.get(&token) {
+            stream.set_read_paused(paused);
+        }
+    }
+
+    pub fn pause_write(&mut self, token: Token, paused: bool) {
+        if let Some(stream) = self.streams.get(&token) {
+            stream.set_write_paused(paused);
+        }
+    }
+
+    /// Reregister the stream with its current interests, which include writability
+    /// unless the state holds the writer or paused writing.
+    pub fn arm_write(&mut self, token: Token) {
+        if let Some(stream) = self.streams.get(&token) {
+            stream.mark_dirty();
+        }
+    }
+
+    fn add_addr(&mut self, addr: SocketAddr, token: Token, incoming: bool) {
+        self.addrs.entry(addr).or_default().push(token);
+        let count = self.ips.entry(addr.ip()).or_default();
+        if incoming {
+            count.incoming += 1;
+        } else {
+            count.outgoing += 1;
+        }
+    }
+
+    fn remove_addr(&mut self, addr: SocketAddr, token: Token, incoming: bool) {
+        if let Some(tokens) = self.addrs.get_mut(&addr) {
+            tokens.retain(|t| *t != token);
+            if tokens.is_empty() {
+                self.addrs.remove(&addr);
+            }
+        }
+        if let Some(count) = self.ips.get_mut(&addr.ip()) {
+            if incoming {
+                count.incoming -= 1;
+            } else {
+                count.outgoing -= 1;
+            }
+            if count.incoming == 0 && count.outgoing == 0 {
+                self.ips.remove(&addr.ip());
+            }
+        }
+    }
+
+    fn remove_peer(&mut self, token: Token) -> Option<ManagedStream> {
+        let stream = self.streams.remove(&token)?;
+        self.remove_addr(stream.addr(), token, stream.incoming());
+        self.release_token(token);
+        let deregistered = self
+            .poll
+            .registry()
+            .deregister(stream.borrow_mut().as_mut());
+        if let Err(e) = deregistered {
+            // the socket is closed anyway, so the poll forgets it
+            self.registration_error(Some(stream.addr()), e);
+        }
+        Some(stream)
+    }
+
+    /// The tokens of the streams closed before the previous run can be given to new streams.
+    /// Call it once per run.
+    /// Returns the tokens which are free from now.
+    pub fn recycle_tokens(&mut self) -> &[Token] {
+        use std::mem;
+
+        let start = self.free_tokens.len();
+        self.free_tokens.append(&mut self.cooling);
+        mem::swap(&mut self.cooling, &mut self.released);
+        &self.free_tokens[start..]
+    }
+
+    // the reserved token stays out of the free tokens until the state releases it
+    fn release_token(&mut self, token: Token) {
+        if !self.reserved.contains(&token) {
+            self.released.push(token);
+        }
+    }
+
+    /// Keep the token after the stream is closed, so the reconnect gets it any time later.
+    /// Returns false if the token is neither in use nor closed recently.
+    pub fn reserve_token(&mut self, token: Token) -> bool {
+        if self.reserved.contains(&token) {
+            return true;
+        }
+        if !self.streams.contains_key(&token) && !self.claim_token(token) {
+            return false;
+        }
+        self.reserved.insert(token);
+        true
+    }
+
+    /// The reserved token passes through the released tokens, once its stream is closed.
+    pub fn release_reserved_token(&mut self, token: Token) {
+        if self.reserved.remove(&token) && !self.streams.contains_key(&token) {
+            self.released.push(token);
+        }
+    }
+
+    // the token of the closed stream, which is not given to another stream yet
+    fn claim_token(&mut self, token: Token) -> bool {
+        for tokens in [&mut self.released, &mut self.cooling, &mut self.free_tokens] {
+            if let Some(pos) = tokens.iter().position(|t| *t == token) {
+                tokens.swap_remove(pos);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Connect again with the token of the previous connection, the live connection
+    /// with the token is closed, not reported. Fails if the token is given to another stream,
+    /// the live connection with another address is not touched.
+    pub fn reconnect_peer(&mut self, token: Token, addr: SocketAddr) -> io::Result<Token> {
+        if let Some(stream) = self.streams.get(&token) {
+            if stream.addr() != addr {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "the token is given to the connection with another address",
+                ));
+            }
+            self.abort_peer(token);
+        }
+        if !self.reserved.contains(&token) && !self.claim_token(token) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "the token is given to another connection",
+            ));
+        }
+        match self.connect(addr, addr, None, false, Some(token)) {
+            Ok(_) => Ok(token),
+            Err(e) => {
+                self.release_token(token);
+                Err(e)
+            },
+        }
+    }
+
     fn register_stream(
         &mut self,
         stream: TcpStream,
         addr: SocketAddr,
+        incoming: bool,
         interests: Interest,
-    ) -> Token {
-        let token = self.allocate_token();
-        let stream = ManagedStream::new(stream, token);
-        self.poll
-            .registry()
-            .register(stream.borrow_mut().as_mut(), token, interests)
-            .expect("bug");
-        self.streams.insert(addr, stream);
-        self.in_progress.insert(token, addr);
-        token
-    }
-
-    pub fn connect_peer(&mut self, addr: SocketAddr) -> Option<Token> {
-        if !self.streams.contains_key(&addr) {
-            match TcpStream::connect(addr) {
-                Ok(stream) => Some(self.register_stream(stream, addr, Interest::WRITABLE)),
-                Err(e) => {
-                    self.error.connect_errors.push((addr, e));
-                    None
-                },
+        token: Option<Token>,
+    ) -> io::Result<Token> {
+        // the token given by the caller is released by the caller
+        let allocated = token.is_none();
+        let token = token.unwrap_or_else(|| self.allocate_token());
+        let mut stream = ManagedStream::new(
+            stream,
+            addr,
+            token,
+            incoming,
+            self.options,
+            self.dirty.clone(),
+            self.totals.clone(),
+        );
+        stream.arm(interests);
+        let registered =
+            self.poll
+                .registry()
+                .register(stream.borrow_mut().as_mut(), token, interests);
+        #[cfg(test)]
+        let registered = registered.and_then(|()| match self.fail_register {
+            true => Err(io::Error::other("the registration failed")),
+            false => Ok(()),
+        });
+        if let Err(e) = registered {
+            if allocated {
+                self.release_token(token);
+            }
+            return Err(e);
+        }
+        self.streams.insert(token, stream);
+        self.add_addr(addr, token, incoming);
+        Ok(token)
+    }
+
+    /// Manage the stream connected already, e.g. by another library.
+    pub fn adopt_stream(&mut self, stream: std::net::TcpStream) -> io::Result<(SocketAddr, Token)> {
+        let addr = stream.peer_addr()?;
+        stream.set_nonblocking(true)?;
+        let stream = TcpStream::from_std(stream);
+        let interests = Interest::READABLE | Interest::WRITABLE;
+        let token = self.register_stream(stream, addr, false, interests, None)?;
+        Ok((addr, token))
+    }
+
+    /// Initiate the connection to the first queued address which allows it,
+    /// the addresses which failed are dropped from the queue.
+    pub fn connect_next(
+        &mut self,
+        queued: &mut SmallVec<[SocketAddr; 4]>,
+        dedup: bool,
+    ) -> Option<(SocketAddr, Connected)> {
+        while !queued.is_empty() {
+            let addr = queued.remove(0);
+            match self.try_connect_peer(addr, None, dedup) {
+                Ok(connected) => return Some((addr, connected)),
+                Err(e) => log::warn!("failed to connect: {}, error: {}", addr, e),
             }
-        } else {
-            None
         }
+        None
     }
 
+    pub fn connect_peer(
+        &mut self,
+        addr: SocketAddr,
+        local: Option<SocketAddr>,
+        dedup: bool,
+    ) -> Option<Connected> {
+        // not an error, the state asked to connect to the peer it blacklisted
+        if self.refuses(addr) {
+            return None;
+        }
+        match self.try_connect_peer(addr, local, dedup) {
+            Ok(connected) => Some(connected),
+            Err(e) => {
+                self.error.connect_errors.push((addr, e));
+                None
+            },
+        }
+    }
+
+    /// Same as `connect_peer`, but the error is not reported.
+    /// Gives the existing connection with the address, if any, only if `dedup`.
+    pub fn try_connect_peer(
+        &mut self,
+        addr: SocketAddr,
+        local: Option<SocketAddr>,
+        dedup: bool,
+    ) -> io::Result<Connected> {
+        self.connect(addr, addr, local, dedup, None)
+    }
+
+    /// Connect to the proxy, the connection is known by the address of the target.
+    pub fn try_connect_via(
+        &mut self,
+        proxy: SocketAddr,
+        target: SocketAddr,
+        dedup: bool,
+    ) -> io::Result<Connected> {
+        self.connect(proxy, target, None, dedup, None)
+    }
+
+    fn connect(
+        &mut self,
+        remote: SocketAddr,
+        addr: SocketAddr,
+        local: Option<SocketAddr>,
+        dedup: bool,
+        token: Option<Token>,
+    ) -> io::Result<Connected> {
+        if let Some(token) = self.token_of(&addr).filter(|_| dedup) {
+            return Ok(Connected::Existing(token));
+        }
+        if self.refuses(addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the peer is blacklisted",
+            ));
+        }
+        if self.full() {
+            return Err(io::Error::other("too many connections"));
+        }
+        if self.ip_full(addr.ip(), false) {
+            return Err(io::Error::other("too many connections with the ip"));
+        }
+        self.metrics.connect_attempts += 1;
+        let stream = match local {
+            None => TcpStream::connect(remote),
+            Some(local) => {
+                let socket = match local {
+                    SocketAddr::V4(_) => TcpSocket::new_v4(),
+                    SocketAddr::V6(_) => TcpSocket::new_v6(),
+                };
+                socket.and_then(|socket| {
+                    socket.bind(local)?;
+                    socket.connect(remote)
+                })
+            },
+        };
+        let stream = stream.inspect_err(|_| self.metrics.connect_failures += 1)?;
+        let token = self.register_stream(stream, addr, false, Interest::WRITABLE, token)?;
+        Ok(Connected::New(token))
+    }
+
+    /// Same as `is_connected`, but does not take the error, the state will see it.
+    pub fn is_established(&self, token: Token) -> bool {
+        self.streams
+            .get(&token)
+            .is_some_and(|stream| stream.borrow_mut().stream.peer_addr().is_ok())
+    }
+
+    /// Whether the outgoing connection is established yet, the error if it failed.
+    pub fn is_connected(&self, token: Token) -> io::Result<bool> {
+        let stream = match self.streams.get(&token) {
+            Some(stream) => stream,
+            None => return Err(io::ErrorKind::NotConnected.into()),
+        };
+        let s = stream.borrow_mut();
+        if let Some(e) = s.stream.take_error()? {
+            return Err(e);
+        }
+        match s.stream.peer_addr() {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Perform the handshake with the proxy as far as the stream allows,
+    /// returns whether the tunnel is established.
+    pub fn socks5_step(
+        &mut self,
+        token: Token,
+        handshake: &mut Socks5Handshake,
+    ) -> io::Result<bool> {
+        if handshake.is_connecting() {
+            if !self.is_connected(token)? {
+                return Ok(false);
+            }
+            handshake.connected();
+            // the handshake both reads and writes, the state did not take the stream yet
+            let interests = Interest::READABLE | Interest::WRITABLE;
+            // `is_connected` found the stream, it cannot be missing
+            let stream = self.streams.get_mut(&token).expect("bug");
+            self.poll
+                .registry()
+                .reregister(stream.borrow_mut().as_mut(), token, interests)?;
+            stream.arm(interests);
+        }
+        let stream = self
+            .streams
+            .get(&token)
+            .ok_or(io::ErrorKind::NotConnected)?;
+        let s = stream.borrow_mut();
+        handshake.advance(&s.stream)
+    }
+
+    /// Reregister only the streams which were taken or whose `ReadOnce` or `WriteOnce`
+    /// was dropped, interests of other streams did not change.
     pub fn reregister(&mut self) {
-        self.streams.retain(|_, stream| !stream.closed());
-        for (addr, stream) in &self.streams {
+        use std::mem;
+
+        self.pending_readers.clear();
+        let mut dirty = mem::take(&mut self.dirty_spare);
+        mem::swap(&mut *self.dirty.lock(), &mut dirty);
+        dirty.sort_unstable();
+        dirty.dedup();
+        for token in dirty.drain(..) {
+            let stream = match self.streams.get_mut(&token) {
+                Some(stream) => stream,
+                None => continue,
+            };
+            if stream.closed() {
+                let reason = stream.close_reason();
+                let addr = stream.addr();
+                let incoming = stream.incoming();
+                self.disconnected
+                    .push((token, addr, reason, stream.stats()));
+                self.streams.remove(&token);
+                self.remove_addr(addr, token, incoming);
+                self.release_token(token);
+                continue;
+            }
+            if stream.read_pending() {
+                self.pending_readers.push(token);
+            }
             if let Some(i) = stream.interests() {
+                let reregistered =
+                    self.poll
+                        .registry()
+                        .reregister(stream.borrow_mut().as_mut(), token, i);
+                match reregistered {
+                    Ok(()) => stream.arm(i),
+                    Err(e) => {
+                        // the connection cannot make progress, drop it
+                        let reason = DisconnectReason::Error(e.kind());
+                        if let Some(stream) = self.remove_peer(token) {
+                            let addr = stream.addr();
+                            self.disconnected
+                                .push((token, addr, reason, stream.stats()));
+                            self.registration_error(Some(addr), e);
+                        }
+                    },
+                }
+            }
+        }
+        self.dirty_spare = dirty;
+        let paused = self.listener_paused;
+        if let Some(listener) = self.listener.as_mut().filter(|_| !paused) {
+            let reregistered =
                 self.poll
                     .registry()
-                    .reregister(stream.borrow_mut().as_mut(), stream.token(), i)
-                    .expect("bug");
-                self.in_progress.insert(stream.token(), *addr);
+                    .reregister(listener, Self::LISTENER, Interest::READABLE);
+            if let Err(e) = reregistered {
+                self.listener = None;
+                self.registration_error(None, e);
             }
         }
-        if let Some(listener) = &mut self.listener {
-            self.poll
-                .registry()
-                .reregister(listener, Self::LISTENER, Interest::READABLE)
-                .expect("bug");
+    }
+
+    pub fn take_disconnected(
+        &mut self,
+    ) -> impl Iterator<Item = (Token, SocketAddr, DisconnectReason, ConnectionStats)> + '_ {
+        self.disconnected.drain(..)
+    }
+
+    /// Returns false if the queue would exceed the limit, or the stream takes no more data.
+    pub fn queue_write(&mut self, token: Token, data: &[u8], frame: bool) -> bool {
+        match self.streams.get(&token) {
+            Some(stream) => stream.queue(data, frame),
+            None => true,
         }
     }
 
+    pub fn queue_closed(&self, token: Token) -> bool {
+        self.streams
+            .get(&token)
+            .is_some_and(ManagedStream::queue_closed)
+    }
+
+    /// Write the queued data of the writable stream, without taking it.
+    /// Returns whether the queue is drained, as `ManagedStream::flush` does.
+    pub fn flush_queued(&mut self, token: Token) -> Option<bool> {
+        let stream = self.streams.get(&token)?;
+        let drained = stream.flush();
+        if drained.is_some() {
+            // the stream might be shut down or failed, reregister it
+            stream.mark_dirty();
+        }
+        drained
+    }
+
+    /// The queue of the failed stream is dropped, so it does not count.
+    pub fn has_queued(&self) -> bool {
+        self.streams.values().any(|stream| stream.queued_len() != 0)
+    }
+
+    pub fn set_queue_limit(&mut self, token: Token, limit: usize) {
+        if let Some(stream) = self.streams.get(&token) {
+            stream.set_queue_limit(Some(limit));
+        }
+    }
+
+    pub fn queued_frames(&self, token: Token) -> Option<usize> {
+        self.streams.get(&token).map(ManagedStream::queued_frames)
+    }
+
+    pub fn queued_len(&self, token: Token) -> Option<usize> {
+        self.streams.get(&token).map(ManagedStream::queued_len)
+    }
+
+    pub fn interest_of(&self, token: Token) -> Option<ConnectionInterest> {
+        let stream = self.streams.get(&token)?;
+        let interests = stream.armed_interests();
+        Some(ConnectionInterest {
+            readable: interests.is_some_and(|i| i.is_readable()),
+            writable: interests.is_some_and(|i| i.is_writable()),
+        })
+    }
+
+    #[cfg(unix)]
+    pub fn raw_fd(&self, token: Token) -> Option<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+
+        let stream = self.streams.get(&token).filter(|s| !s.closed())?;
+        let fd = stream.borrow_mut().stream.as_raw_fd();
+        Some(fd)
+    }
+
+    #[cfg(windows)]
+    pub fn raw_socket(&self, token: Token) -> Option<std::os::windows::io::RawSocket> {
+        use std::os::windows::io::AsRawSocket;
+
+        let stream = self.streams.get(&token).filter(|s| !s.closed())?;
+        let socket = stream.borrow_mut().stream.as_raw_socket();
+        Some(socket)
+    }
+
+    pub fn set_socket_option(&mut self, token: Token, option: SocketOption) {
+        use socket2::{SockRef, TcpKeepalive};
+
+        let stream = match self.streams.get(&token) {
+            Some(stream) => stream,
+            None => return,
+        };
+        let s = stream.borrow_mut();
+        let socket = SockRef::from(&s.stream);
+        let result = match option {
+            SocketOption::Keepalive(None) => socket.set_keepalive(false),
+            SocketOption::Keepalive(Some(idle)) => {
+                socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))
+            },
+            SocketOption::RecvBuffer(size) => socket.set_recv_buffer_size(size).and_then(|()| {
+                let size = socket.recv_buffer_size()?;
+                log::debug!("receive buffer of {}: {}", stream.addr(), size);
+                Ok(())
+            }),
+            SocketOption::SendBuffer(size) => socket.set_send_buffer_size(size).and_then(|()| {
+                let size = socket.send_buffer_size()?;
+                log::debug!("send buffer of {}: {}", stream.addr(), size);
+                Ok(())
+            }),
+        };
+        if let Err(e) = result {
+            self.error.socket_errors.push((stream.addr(), e));
+        }
+    }
+
+    pub fn has_pending_readers(&self) -> bool {
+        !self.pending_readers.is_empty()
+    }
+
+    pub fn pending_readers(&self) -> impl Iterator<Item = Token> + '_ {
+        self.pending_readers.iter().cloned()
+    }
+
+    pub fn addr_of(&self, token: Token) -> Option<SocketAddr> {
+        self.streams
+            .get(&token)
+            .filter(|stream| !stream.closed())
+            .map(ManagedStream::addr)
+    }
+
+    pub fn stats_of(&self, token: Token) -> Option<ConnectionStats> {
+        self.streams.get(&token).map(ManagedStream::stats)
+    }
+
+    /// The oldest live connection with the address.
+    pub fn token_of(&self, addr: &SocketAddr) -> Option<Token> {
+        self.tokens_of(addr).into_iter().next()
+    }
+
+    /// The open connections, in order of their tokens.
+    pub fn peers(&self) -> Vec<(Token, SocketAddr)> {
+        let mut peers = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| !stream.closed())
+            .map(|(token, stream)| (*token, stream.addr()))
+            .collect::<Vec<_>>();
+        peers.sort_unstable();
+        peers
+    }
+
+    pub fn tokens_of(&self, addr: &SocketAddr) -> SmallVec<[Token; 1]> {
+        let tokens = match self.addrs.get(addr) {
+            Some(tokens) => tokens,
+            None => return SmallVec::new(),
+        };
+        tokens
+            .iter()
+            .filter(|token| self.streams.get(token).is_some_and(|s| !s.closed()))
+            .cloned()
+            .collect()
+    }
+
     pub fn take_stream(&mut self, token: &Token) -> Option<(SocketAddr, &ManagedStream)> {
-        let addr = self.in_progress.remove(token)?;
-        Some((addr, self.streams.get(&addr).unwrap()))
+        let stream = self.streams.get_mut(token)?;
+        if !stream.disarm() {
+            return None;
+        }
+        // must be armed again after the proposer handled the event
+        stream.mark_dirty();
+        Some((stream.addr(), stream))
     }
 
-    pub fn accept(&mut self) -> Option<(SocketAddr, Token)> {
-        let listener = self.listener.as_ref()?;
+    /// Takes a single connection, `None` if there is nothing to accept now.
+    pub fn accept(&mut self) -> Option<Accepted> {
+        if self.listener_paused {
+            return None;
+        }
+        let listener = self.listener.as_mut()?;
+        if !self.accept_rate.as_ref().is_none_or(TokenBucket::available) {
+            // stop accepting rather than accept and drop
+            match self.poll.registry().deregister(listener) {
+                Ok(()) => self.listener_paused = true,
+                Err(e) => self.registration_error(None, e),
+            }
+            return None;
+        }
         let (stream, addr) = match listener.accept() {
             Ok(v) => v,
             Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
@@ -153,19 +1117,252 @@ impl StreamRegistry {
                 return None;
             },
         };
-        let token = self.register_stream(stream, addr, Interest::READABLE);
-        Some((addr, token))
+        if let Some(bucket) = &mut self.accept_rate {
+            bucket.take();
+        }
+        // the ipv4 peer of the dual stack listener
+        let addr = match addr {
+            SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+                None => addr,
+            },
+            addr => addr,
+        };
+        if self.blacklist.contains(&addr.ip()) {
+            log::info!("blacklisted, drop incoming: {}", addr);
+            drop(stream);
+            self.reject(addr, RejectReason::Blacklisted);
+            return Some(Accepted::Dropped);
+        }
+        if self.full() {
+            log::warn!("too many connections, drop incoming: {}", addr);
+            drop(stream);
+            self.reject(addr, RejectReason::MaxConnections);
+            return Some(Accepted::Dropped);
+        }
+        if self.ip_full(addr.ip(), true) {
+            log::warn!("too many connections with the ip, drop incoming: {}", addr);
+            drop(stream);
+            self.reject(addr, RejectReason::PerIpLimit);
+            return Some(Accepted::Dropped);
+        }
+        let token = match self.register_stream(stream, addr, true, Interest::READABLE, None) {
+            Ok(token) => token,
+            Err(e) => {
+                self.registration_error(Some(addr), e);
+                return None;
+            },
+        };
+        self.metrics.connections_accepted += 1;
+        Some(Accepted::New(addr, token))
     }
 
-    pub fn poll(&mut self, events: &mut Events, timeout: Duration) {
-        if let Err(e) = self.poll.poll(events, Some(timeout)) {
-            if e.kind() != io::ErrorKind::Interrupted {
-                self.error.poll_error = Some(e);
-            }
+    /// Returns whether the poll finished because the timeout elapsed.
+    pub fn poll(&mut self, events: &mut Events, timeout: Duration) -> bool {
+        self.metrics.poll_iterations += 1;
+        match self.poll.poll(events, Some(timeout)) {
+            Ok(()) => {
+                let count = events.iter().count();
+                self.metrics.polled_events += count as u64;
+                if count == 0 {
+                    self.metrics.poll_timeouts += 1;
+                } else if count >= events.capacity() {
+                    self.metrics.saturated_polls += 1;
+                }
+                count == 0
+            },
+            Err(e) => {
+                if e.kind() != io::ErrorKind::Interrupted {
+                    self.error.poll_error = Some(e);
+                }
+                false
+            },
         }
     }
 
+    #[allow(clippy::result_large_err)]
     pub fn take_result(&mut self) -> Result<(), ProposerError> {
         self.error.take_result()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use mio::Token;
+
+    use crate::request::ConnectionSource;
+    use super::{StreamRegistry, TokenKind, Connected, Accepted};
+
+    #[test]
+    fn reserved_tokens_after_wrap_around() {
+        let mut registry = StreamRegistry::new().unwrap();
+        registry.last_token = Token(usize::MAX - 3);
+        let tokens = (0..5)
+            .map(|_| registry.allocate_token())
+            .collect::<Vec<_>>();
+        let expected = [usize::MAX - 3, usize::MAX - 2, 0, 1, 2];
+        assert_eq!(tokens, expected.map(Token));
+        assert!(registry.wrapped);
+    }
+
+    #[test]
+    fn tokens_in_use_after_wrap_around() {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut registry = StreamRegistry::new().unwrap();
+        registry.last_token = Token(usize::MAX - 1);
+        let token = match registry.connect_peer(addr, None, false) {
+            Some(Connected::New(token)) => token,
+            _ => panic!("failed to connect"),
+        };
+        assert_eq!(token, Token(0));
+
+        // go around once more, the token of the live stream is skipped as well
+        registry.last_token = Token(usize::MAX - 1);
+        for _ in 0..4 {
+            let token = registry.allocate_token();
+            assert_eq!(StreamRegistry::classify(token), TokenKind::Connection);
+            assert_ne!(token, Token(0));
+        }
+    }
+
+    #[test]
+    fn per_ip_count() {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut registry = StreamRegistry::new().unwrap();
+        registry.set_per_ip_limit(Some(2));
+        let first = match registry.connect_peer(addr, None, false) {
+            Some(Connected::New(token)) => token,
+            _ => panic!("failed to connect"),
+        };
+        // the outgoing connections are not limited unless configured
+        assert!(!registry.ip_full(addr.ip(), true));
+        registry.set_per_ip_outgoing(true);
+        assert!(registry.connect_peer(addr, None, false).is_some());
+        assert!(registry.connect_peer(addr, None, false).is_none());
+
+        registry.abort_peer(first);
+        assert!(!registry.ip_full(addr.ip(), false));
+        assert!(registry.connect_peer(addr, None, false).is_some());
+        assert!(registry.ip_full(addr.ip(), false));
+        assert!(!registry.ip_full([127, 0, 0, 2].into(), false));
+    }
+
+    #[test]
+    fn accept_would_block() {
+        let mut registry = StreamRegistry::new().unwrap();
+        let addr = registry.set_source(ConnectionSource::Port(0)).unwrap();
+        // nothing to accept, not an error
+        assert!(registry.accept().is_none());
+        assert!(registry.take_result().is_ok());
+        assert_eq!(registry.listening_addr(), Some(addr));
+
+        let local = SocketAddr::from(([127, 0, 0, 1], addr.port()));
+        let _stream = std::net::TcpStream::connect(local).unwrap();
+        // the connection might take a moment to reach the queue of the listener
+        let mut accepted = None;
+        for _ in 0..100 {
+            accepted = registry.accept();
+            if accepted.is_some() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(matches!(accepted, Some(Accepted::New(..))));
+        assert!(registry.accept().is_none());
+        assert!(registry.take_result().is_ok());
+    }
+
+    #[test]
+    fn accept_dropped() {
+        let mut registry = StreamRegistry::new().unwrap();
+        let addr = registry.set_source(ConnectionSource::Port(0)).unwrap();
+        let local = SocketAddr::from(([127, 0, 0, 1], addr.port()));
+        registry.blacklist_ip(local.ip());
+        let _streams = (0..3)
+            .map(|_| std::net::TcpStream::connect(local).unwrap())
+            .collect::<Vec<_>>();
+        // a single connection per call, even if it is dropped
+        let mut dropped = 0;
+        for _ in 0..100 {
+            match registry.accept() {
+                Some(Accepted::Dropped) => dropped += 1,
+                Some(Accepted::New(..)) => panic!("the blacklisted peer is accepted"),
+                None if dropped == 3 => break,
+                None => std::thread::sleep(std::time::Duration::from_millis(1)),
+            }
+        }
+        assert_eq!(dropped, 3);
+    }
+
+    #[test]
+    fn reconnect_register_fails() {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut registry = StreamRegistry::new().unwrap();
+        let token = match registry.connect_peer(addr, None, false) {
+            Some(Connected::New(token)) => token,
+            _ => panic!("failed to connect"),
+        };
+        registry.fail_register = true;
+        assert!(registry.reconnect_peer(token, addr).is_err());
+        registry.fail_register = false;
+
+        // the token is free once, not twice
+        registry.recycle_tokens();
+        assert_eq!(registry.recycle_tokens(), [token]);
+        let tokens = (0..2)
+            .map(|_| match registry.connect_peer(addr, None, false) {
+                Some(Connected::New(token)) => token,
+                _ => panic!("failed to connect"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(tokens[0], token);
+        assert_ne!(tokens[1], token);
+    }
+
+    #[test]
+    fn blacklist_ip_in_order() {
+        let listeners = (0..8)
+            .map(|_| TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut registry = StreamRegistry::new().unwrap();
+        for listener in &listeners {
+            let addr = listener.local_addr().unwrap();
+            assert!(registry.connect_peer(addr, None, false).is_some());
+        }
+        registry.blacklist_ip([127, 0, 0, 1].into());
+        let tokens = registry
+            .take_disconnected()
+            .map(|(token, ..)| token)
+            .collect::<Vec<_>>();
+        let mut sorted = tokens.clone();
+        sorted.sort();
+        assert_eq!(tokens.len(), listeners.len());
+        assert_eq!(tokens, sorted);
+    }
+
+    #[test]
+    fn full_counts_live_streams() {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut registry = StreamRegistry::new().unwrap();
+        registry.set_max_connections(Some(1));
+        let token = match registry.connect_peer(addr, None, false) {
+            Some(Connected::New(token)) => token,
+            _ => panic!("failed to connect"),
+        };
+        assert!(registry.connect_peer(addr, None, false).is_none());
+
+        // closed, but not reaped yet
+        registry.shutdown_peer(token, std::net::Shutdown::Both);
+        assert!(registry.connect_peer(addr, None, false).is_some());
+    }
+}
@@ -0,0 +1,653 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+//! In-memory transport and a harness for testing a state machine
+//! without real sockets and without the proposer.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    net::{SocketAddr, IpAddr, Ipv4Addr},
+    rc::Rc,
+    time::{Duration, Instant},
+    mem,
+};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use super::{
+    proposal::{ProposalKind, ConnectionId, ConnectionOrigin, ReadOnce, WriteOnce, IoResult},
+    request::{Request, ConnectionSource},
+    state::State,
+    time::{TimeTracker, Clock, ManualClock},
+};
+
+#[derive(Default)]
+struct MemPipe {
+    incoming: VecDeque<u8>,
+    outgoing: Vec<u8>,
+    max_read: Option<usize>,
+    max_write: Option<usize>,
+    read_closed: bool,
+    write_closed: bool,
+    broken: bool,
+}
+
+/// The in-memory connection. The test plays the role of the remote peer,
+/// it feeds the bytes the state will read and takes the bytes the state wrote.
+#[derive(Clone, Default)]
+pub struct MemStream(Rc<RefCell<MemPipe>>);
+
+impl MemStream {
+    pub fn new() -> Self {
+        MemStream::default()
+    }
+
+    /// The remote peer sends the data.
+    pub fn feed(&self, data: &[u8]) {
+        self.0.borrow_mut().incoming.extend(data);
+    }
+
+    /// Take all bytes the state wrote so far.
+    pub fn take_written(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.borrow_mut().outgoing)
+    }
+
+    /// How many bytes are fed, but not read yet.
+    pub fn pending(&self) -> usize {
+        self.0.borrow().incoming.len()
+    }
+
+    /// Each read will return at most `max` bytes, simulates short reads.
+    pub fn set_max_read(&self, max: Option<usize>) {
+        self.0.borrow_mut().max_read = max;
+    }
+
+    /// Each write will accept at most `max` bytes, simulates short writes.
+    /// The write of zero bytes reports `WouldBlock`.
+    pub fn set_max_write(&self, max: Option<usize>) {
+        self.0.borrow_mut().max_write = max;
+    }
+
+    /// The remote peer will send nothing more,
+    /// the read which drains the fed data reports `will_close`, the next read is `Eof`.
+    pub fn close_read(&self) {
+        self.0.borrow_mut().read_closed = true;
+    }
+
+    /// The remote peer will accept nothing more, next write reports `will_close`.
+    pub fn close_write(&self) {
+        self.0.borrow_mut().write_closed = true;
+    }
+
+    /// The connection is gone, any read or write returns `IoResult::Closed`.
+    pub fn break_connection(&self) {
+        self.0.borrow_mut().broken = true;
+    }
+
+    pub fn read_once(&self) -> MemReadOnce {
+        MemReadOnce(self.clone())
+    }
+
+    pub fn write_once(&self) -> MemWriteOnce {
+        MemWriteOnce(self.clone())
+    }
+}
+
+pub struct MemReadOnce(MemStream);
+
+impl ReadOnce for MemReadOnce {
+    fn read(self, buf: &mut [u8]) -> IoResult {
+        let mut p = (self.0).0.borrow_mut();
+        if p.broken {
+            return IoResult::Closed;
+        }
+        let length = buf
+            .len()
+            .min(p.max_read.unwrap_or(usize::MAX))
+            .min(p.incoming.len());
+        for (dst, src) in buf.iter_mut().zip(p.incoming.drain(..length)) {
+            *dst = src;
+        }
+        if length == 0 && !buf.is_empty() {
+            return if p.read_closed {
+                IoResult::Eof
+            } else {
+                IoResult::WouldBlock
+            };
+        }
+        let will_close = p.read_closed && p.incoming.is_empty();
+        IoResult::Done { length, will_close }
+    }
+}
+
+pub struct MemWriteOnce(MemStream);
+
+impl WriteOnce for MemWriteOnce {
+    fn write(self, data: &[u8]) -> IoResult {
+        let mut p = (self.0).0.borrow_mut();
+        if p.broken {
+            return IoResult::Closed;
+        }
+        let length = data.len().min(p.max_write.unwrap_or(usize::MAX));
+        if length == 0 && !data.is_empty() {
+            return IoResult::WouldBlock;
+        }
+        p.outgoing.extend_from_slice(&data[..length]);
+        let will_close = p.write_closed;
+        IoResult::Done { length, will_close }
+    }
+}
+
+/// Feeds the proposals to the state the same way the proposer does,
+/// but the io is backed by `MemStream` and the time is advanced explicitly.
+pub struct Harness<Rngs, S>
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<MemReadOnce, MemWriteOnce>,
+{
+    time_tracker: TimeTracker<Rngs, S, MemReadOnce, MemWriteOnce, ManualClock>,
+    poll_id: u16,
+    last_token: u64,
+    streams: BTreeMap<ConnectionId, (SocketAddr, MemStream)>,
+}
+
+impl<Rngs, S> AsMut<S> for Harness<Rngs, S>
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<MemReadOnce, MemWriteOnce>,
+{
+    fn as_mut(&mut self) -> &mut S {
+        self.time_tracker.as_mut()
+    }
+}
+
+impl<Rngs, S> AsRef<S> for Harness<Rngs, S>
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<MemReadOnce, MemWriteOnce>,
+{
+    fn as_ref(&self) -> &S {
+        self.time_tracker.as_ref()
+    }
+}
+
+impl<Rngs, S> Harness<Rngs, S>
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<MemReadOnce, MemWriteOnce>,
+{
+    pub fn new(rngs: Rngs, state: S) -> Self {
+        Harness::with_clock(rngs, state, ManualClock::new())
+    }
+
+    /// The harnesses constructed with clones of the clock share the time.
+    pub fn with_clock(rngs: Rngs, state: S, clock: ManualClock) -> Self {
+        Harness {
+            time_tracker: TimeTracker::with_clock(rngs, state, clock),
+            poll_id: 0,
+            last_token: 0,
+            streams: BTreeMap::default(),
+        }
+    }
+
+    /// The next proposal will report this much more elapsed time.
+    /// The timers due by then fire, the state receives `Timer` for each.
+    pub fn advance(&mut self, duration: Duration) -> Request {
+        self.time_tracker.advance(duration)
+    }
+
+    /// Fire the timers due by the clock, e.g. the clock is shared and advanced elsewhere.
+    pub fn fire_timers(&mut self) -> Option<Request> {
+        self.time_tracker.fire_timers()
+    }
+
+    /// Send an arbitrary proposal.
+    pub fn send(&mut self, kind: ProposalKind<MemReadOnce, MemWriteOnce, S::Ext>) -> Request {
+        self.time_tracker.send(kind)
+    }
+
+    pub fn wake(&mut self) -> Request {
+        self.send(ProposalKind::Wake)
+    }
+
+    pub fn idle(&mut self) -> Request {
+        self.send(ProposalKind::Idle { timed_out: true })
+    }
+
+    pub fn custom(&mut self, ext: S::Ext) -> Request {
+        self.send(ProposalKind::Custom(ext))
+    }
+
+    /// Create a new in-memory connection and tell the state about it.
+    pub fn connect(
+        &mut self,
+        addr: SocketAddr,
+        origin: ConnectionOrigin,
+    ) -> (ConnectionId, Request) {
+        let id = ConnectionId {
+            poll_id: self.poll_id,
+            token: self.last_token,
+        };
+        self.last_token += 1;
+        self.streams.insert(id, (addr, MemStream::new()));
+        let incoming = matches!(origin, ConnectionOrigin::Listener(_));
+        let request = self.send(ProposalKind::Connection {
+            addr,
+            incoming,
+            origin,
+            id,
+        });
+        (id, request)
+    }
+
+    /// The stream behind the connection, to script the remote peer.
+    pub fn stream(&self, id: ConnectionId) -> Option<&MemStream> {
+        self.streams.get(&id).map(|(_, stream)| stream)
+    }
+
+    /// Feed the data and tell the state the connection is readable.
+    pub fn readable(&mut self, id: ConnectionId, data: &[u8]) -> Request {
+        let (addr, stream) = self.streams.get(&id).expect("no such connection");
+        stream.feed(data);
+        let kind = ProposalKind::OnReadable(id, *addr, stream.read_once());
+        self.send(kind)
+    }
+
+    /// Tell the state the connection is writable.
+    pub fn writable(&mut self, id: ConnectionId) -> Request {
+        let (addr, stream) = self.streams.get(&id).expect("no such connection");
+        let kind = ProposalKind::OnWritable(id, *addr, stream.write_once());
+        self.send(kind)
+    }
+
+    /// Take all bytes the state wrote to the connection so far.
+    pub fn written(&self, id: ConnectionId) -> Vec<u8> {
+        self.streams
+            .get(&id)
+            .map(|(_, stream)| stream.take_written())
+            .unwrap_or_default()
+    }
+}
+
+struct Node<Rngs, S>
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<MemReadOnce, MemWriteOnce>,
+{
+    harness: Harness<Rngs, S>,
+    ip: IpAddr,
+    listening: Option<u16>,
+    started: bool,
+    // the proposer has something to tell the state
+    active: bool,
+    request: Request,
+    readable: BTreeSet<ConnectionId>,
+    writable: BTreeSet<ConnectionId>,
+    queued: BTreeSet<ConnectionId>,
+    drained: BTreeSet<ConnectionId>,
+}
+
+impl<Rngs, S> Node<Rngs, S>
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<MemReadOnce, MemWriteOnce>,
+{
+    fn send(&mut self, kind: ProposalKind<MemReadOnce, MemWriteOnce, S::Ext>) {
+        self.active = true;
+        self.request += self.harness.send(kind);
+    }
+
+    fn connect(&mut self, addr: SocketAddr, origin: ConnectionOrigin) -> ConnectionId {
+        self.active = true;
+        let (id, request) = self.harness.connect(addr, origin);
+        self.request += request;
+        self.writable.insert(id);
+        id
+    }
+
+    /// The state learns about it on the next read.
+    fn disconnected(&mut self, id: ConnectionId) {
+        if let Some(stream) = self.harness.stream(id) {
+            stream.close_read();
+            stream.close_write();
+            self.readable.insert(id);
+        }
+    }
+}
+
+/// How the bytes travel between two nodes. The bytes written by the state
+/// in one step are a chunk, the chunk is delivered after `latency` plus
+/// a random delay up to `jitter`, or lost with the `loss` probability.
+/// The jitter can reorder the chunks.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct LinkConditions {
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub loss: f64,
+}
+
+/// The chunk of bytes on the way to the node.
+struct InFlight {
+    to: (usize, ConnectionId),
+    data: Vec<u8>,
+}
+
+/// The pair of the connected nodes and the connection id on each side.
+#[derive(Clone, Copy)]
+struct Link {
+    a: (usize, ConnectionId),
+    b: (usize, ConnectionId),
+}
+
+/// Runs several states on the virtual network, each state is a node.
+/// The nodes connect to each other through in-memory connections,
+/// the time is virtual and shared, the order of proposals is deterministic.
+///
+/// The simulation supports listening, connecting, disconnecting and queued writes,
+/// other requests are ignored. The connection is always writable,
+/// the state receives `OnWritable` when connected and after each write.
+/// The links between the nodes can delay and lose the data, see `LinkConditions`.
+pub struct Simulation<Rngs, S>
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<MemReadOnce, MemWriteOnce>,
+{
+    clock: ManualClock,
+    nodes: Vec<Node<Rngs, S>>,
+    links: Vec<Link>,
+    next_port: u16,
+    rng: StdRng,
+    default_conditions: LinkConditions,
+    conditions: BTreeMap<(usize, usize), LinkConditions>,
+    // ordered by the delivery time and then by the sequence number
+    in_flight: BTreeMap<(Instant, u64), InFlight>,
+    sequence: u64,
+}
+
+impl<Rngs, S> Default for Simulation<Rngs, S>
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<MemReadOnce, MemWriteOnce>,
+{
+    fn default() -> Self {
+        Simulation::new()
+    }
+}
+
+impl<Rngs, S> Simulation<Rngs, S>
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<MemReadOnce, MemWriteOnce>,
+{
+    pub fn new() -> Self {
+        Simulation::with_seed(0)
+    }
+
+    /// The seed of the random generator of the network, it decides
+    /// which chunks are lost and how they are delayed.
+    pub fn with_seed(seed: u64) -> Self {
+        Simulation {
+            clock: ManualClock::new(),
+            nodes: Vec::new(),
+            links: Vec::new(),
+            next_port: 49152,
+            rng: StdRng::seed_from_u64(seed),
+            default_conditions: LinkConditions::default(),
+            conditions: BTreeMap::new(),
+            in_flight: BTreeMap::new(),
+            sequence: 0,
+        }
+    }
+
+    /// The conditions of the links between the nodes which are not set explicitly.
+    pub fn set_default_conditions(&mut self, conditions: LinkConditions) {
+        self.default_conditions = conditions;
+    }
+
+    /// The conditions of the links between the two nodes, in both directions.
+    pub fn set_conditions(&mut self, a: usize, b: usize, conditions: LinkConditions) {
+        self.conditions.insert((a.min(b), a.max(b)), conditions);
+    }
+
+    fn conditions(&self, a: usize, b: usize) -> LinkConditions {
+        self.conditions
+            .get(&(a.min(b), a.max(b)))
+            .cloned()
+            .unwrap_or(self.default_conditions)
+    }
+
+    /// Returns the index of the node, its ip address is `10.0.x.y`,
+    /// where `x.y` is the index plus one.
+    pub fn add_node(&mut self, rngs: Rngs, state: S) -> usize {
+        let index = self.nodes.len();
+        let [_, _, x, y] = ((index + 1) as u32).to_be_bytes();
+        let mut harness = Harness::with_clock(rngs, state, self.clock.clone());
+        harness.poll_id = index as u16;
+        self.nodes.push(Node {
+            harness,
+            ip: Ipv4Addr::new(10, 0, x, y).into(),
+            listening: None,
+            started: false,
+            active: false,
+            request: Request::default(),
+            readable: BTreeSet::new(),
+            writable: BTreeSet::new(),
+            queued: BTreeSet::new(),
+            drained: BTreeSet::new(),
+        });
+        index
+    }
+
+    pub fn ip(&self, node: usize) -> IpAddr {
+        self.nodes[node].ip
+    }
+
+    pub fn state(&self, node: usize) -> &S {
+        self.nodes[node].harness.as_ref()
+    }
+
+    pub fn state_mut(&mut self, node: usize) -> &mut S {
+        self.nodes[node].harness.as_mut()
+    }
+
+    pub fn clock(&self) -> &ManualClock {
+        &self.clock
+    }
+
+    /// Run the given number of steps, each advances the time by the `duration`.
+    pub fn run(&mut self, steps: usize, duration: Duration) {
+        for _ in 0..steps {
+            self.step(duration);
+        }
+    }
+
+    /// Advance the time, deliver the written bytes, then for each node in order
+    /// perform its requests and propose what happened, including the timers due.
+    /// The node receives `Idle` if nothing happened for it.
+    pub fn step(&mut self, duration: Duration) {
+        self.clock.advance(duration);
+        for node in &mut self.nodes {
+            node.active = false;
+        }
+        self.deliver();
+        for index in 0..self.nodes.len() {
+            let node = &mut self.nodes[index];
+            if !node.started || node.request.take_wake() {
+                node.started = true;
+                node.send(ProposalKind::Wake);
+            }
+            self.perform(index);
+            self.propose(index);
+        }
+    }
+
+    fn deliver(&mut self) {
+        let now = self.clock.now();
+        for i in 0..self.links.len() {
+            let Link { a, b } = self.links[i];
+            for &((from, from_id), to) in &[(a, b), (b, a)] {
+                let data = self.nodes[from].harness.written(from_id);
+                if data.is_empty() {
+                    continue;
+                }
+                self.nodes[from].writable.insert(from_id);
+                let conditions = self.conditions(from, to.0);
+                if conditions.loss > 0.0 && self.rng.gen_bool(conditions.loss.min(1.0)) {
+                    continue;
+                }
+                let jitter = if conditions.jitter > Duration::ZERO {
+                    self.rng.gen_range(Duration::ZERO..=conditions.jitter)
+                } else {
+                    Duration::ZERO
+                };
+                let at = now + conditions.latency + jitter;
+                self.sequence += 1;
+                self.in_flight
+                    .insert((at, self.sequence), InFlight { to, data });
+            }
+        }
+        while let Some(entry) = self.in_flight.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let InFlight {
+                to: (to, to_id),
+                data,
+            } = entry.remove();
+            if let Some(stream) = self.nodes[to].harness.stream(to_id) {
+                stream.feed(&data);
+                self.nodes[to].readable.insert(to_id);
+            }
+        }
+        for node in &mut self.nodes {
+            let queued = mem::take(&mut node.queued);
+            node.drained.extend(queued);
+        }
+    }
+
+    fn perform(&mut self, index: usize) {
+        let mut request = mem::take(&mut self.nodes[index].request);
+
+        if let Some(source) = request.take_new_source() {
+            match source {
+                ConnectionSource::None => self.nodes[index].listening = None,
+                ConnectionSource::Port(port)
+                | ConnectionSource::ReusePort(port)
+                | ConnectionSource::DualStack(port)
+                | ConnectionSource::PortRange { start: port, .. } => {
+                    let port = if port == 0 {
+                        self.allocate_port()
+                    } else {
+                        port
+                    };
+                    let node = &mut self.nodes[index];
+                    node.listening = Some(port);
+                    let addr = (node.ip, port).into();
+                    node.send(ProposalKind::Listening { addr });
+                },
+            }
+        }
+
+        let streams = &self.nodes[index].harness.streams;
+        let by_addr = request
+            .take_disconnects()
+            .flat_map(|addr| {
+                streams
+                    .iter()
+                    .filter(move |(_, (a, _))| *a == addr)
+                    .map(|(id, _)| *id)
+            })
+            .collect::<Vec<_>>();
+        let ids = request.take_disconnect_ids().chain(request.take_resets());
+        for id in by_addr.into_iter().chain(ids.collect::<Vec<_>>()) {
+            self.disconnect(index, id);
+        }
+
+        for (id, data, ..) in request.take_queued_writes() {
+            let node = &mut self.nodes[index];
+            if let Some(stream) = node.harness.stream(id) {
+                stream.0.borrow_mut().outgoing.extend_from_slice(&data);
+                node.queued.insert(id);
+            }
+        }
+        for (id, length) in request.take_rejected_frames() {
+            self.nodes[index].send(ProposalKind::WriteRejected { id, length });
+        }
+
+        let connects = request.take_connects();
+        let connects_from = request.take_connects_from().map(|(_, addr)| addr);
+        for addr in connects.chain(connects_from).collect::<Vec<_>>() {
+            self.connect(index, addr);
+        }
+    }
+
+    fn propose(&mut self, index: usize) {
+        let node = &mut self.nodes[index];
+        if let Some(request) = node.harness.fire_timers() {
+            node.active = true;
+            node.request += request;
+        }
+        for id in mem::take(&mut node.drained) {
+            node.send(ProposalKind::WriteDrained(id));
+        }
+        for id in mem::take(&mut node.readable) {
+            if node.harness.stream(id).is_some() {
+                node.active = true;
+                node.request += node.harness.readable(id, &[]);
+            }
+        }
+        for id in mem::take(&mut node.writable) {
+            if node.harness.stream(id).is_some() {
+                node.active = true;
+                node.request += node.harness.writable(id);
+            }
+        }
+        if !node.active {
+            node.send(ProposalKind::Idle { timed_out: true });
+        }
+    }
+
+    fn allocate_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = self.next_port.checked_add(1).unwrap_or(49152);
+        port
+    }
+
+    fn connect(&mut self, index: usize, addr: SocketAddr) {
+        let remote = self
+            .nodes
+            .iter()
+            .position(|node| node.ip == addr.ip() && node.listening == Some(addr.port()));
+        let remote = match remote {
+            Some(remote) => remote,
+            // nobody is listening, the connection is refused
+            None => return,
+        };
+        let local = (self.nodes[index].ip, self.allocate_port()).into();
+        let a = self.nodes[index].connect(addr, ConnectionOrigin::Outgoing);
+        let b = self.nodes[remote].connect(local, ConnectionOrigin::Listener(addr));
+        self.links.push(Link {
+            a: (index, a),
+            b: (remote, b),
+        });
+    }
+
+    fn disconnect(&mut self, index: usize, id: ConnectionId) {
+        if let Some((_, stream)) = self.nodes[index].harness.streams.remove(&id) {
+            stream.break_connection();
+        }
+        let pos = self
+            .links
+            .iter()
+            .position(|link| link.a == (index, id) || link.b == (index, id));
+        if let Some(pos) = pos {
+            let link = self.links.remove(pos);
+            let (remote, remote_id) = if link.a == (index, id) {
+                link.b
+            } else {
+                link.a
+            };
+            self.nodes[remote].disconnected(remote_id);
+        }
+    }
+}
@@ -1,38 +1,112 @@
 // Copyright 2021 Vladislav Melnik
 // SPDX-License-Identifier: MIT
 
-use std::{time::Instant, marker::PhantomData};
+use std::{
+    time::{Instant, Duration},
+    marker::PhantomData,
+    rc::Rc,
+    cell::Cell,
+};
 use super::{
     state::State,
     proposal::{Proposal, ProposalKind, ReadOnce, WriteOnce},
     request::Request,
+    timer_wheel::TimerWheel,
 };
 
-pub struct TimeTracker<Rngs, S, R, W>
+/// The source of time, the elapsed time in proposals is measured by it.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The real monotonic clock.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// The clock stands still until it is advanced explicitly.
+/// The clones share the time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: Instant,
+    offset: Rc<Cell<Duration>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            start: Instant::now(),
+            offset: Rc::default(),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.offset.set(self.offset.get() + duration);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.offset.get()
+    }
+}
+
+pub struct TimeTracker<Rngs, S, R, W, C = SystemClock>
 where
     Rngs: Iterator<Item = S::Rng>,
     S: State<R, W>,
     R: ReadOnce,
     W: WriteOnce,
+    C: Clock,
 {
+    clock: C,
+    start: Instant,
     last: Instant,
     rngs: Rngs,
     state: S,
+    // the timers the state set, the proposer fires them through the tracker
+    timers: TimerWheel<u64>,
+    rng_exhausted: bool,
     phantom_data: PhantomData<(R, W)>,
 }
 
-impl<Rngs, S, R, W> AsMut<S> for TimeTracker<Rngs, S, R, W>
+impl<Rngs, S, R, W, C> AsMut<S> for TimeTracker<Rngs, S, R, W, C>
 where
     Rngs: Iterator<Item = S::Rng>,
     S: State<R, W>,
     R: ReadOnce,
     W: WriteOnce,
+    C: Clock,
 {
     fn as_mut(&mut self) -> &mut S {
         &mut self.state
     }
 }
 
+impl<Rngs, S, R, W, C> AsRef<S> for TimeTracker<Rngs, S, R, W, C>
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<R, W>,
+    R: ReadOnce,
+    W: WriteOnce,
+    C: Clock,
+{
+    fn as_ref(&self) -> &S {
+        &self.state
+    }
+}
+
 impl<Rngs, S, R, W> TimeTracker<Rngs, S, R, W>
 where
     Rngs: Iterator<Item = S::Rng>,
@@ -41,24 +115,114 @@ where
     W: WriteOnce,
 {
     pub fn new(rngs: Rngs, state: S) -> Self {
+        TimeTracker::with_clock(rngs, state, SystemClock)
+    }
+}
+
+impl<Rngs, S, R, W, C> TimeTracker<Rngs, S, R, W, C>
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<R, W>,
+    R: ReadOnce,
+    W: WriteOnce,
+    C: Clock,
+{
+    pub fn with_clock(rngs: Rngs, state: S, clock: C) -> Self {
+        let start = clock.now();
         TimeTracker {
-            last: Instant::now(),
+            start,
+            last: start,
+            clock,
             rngs,
             state,
+            timers: TimerWheel::starting_at(start),
+            rng_exhausted: false,
             phantom_data: PhantomData,
         }
     }
 
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// The time of the last proposal since the tracker is created.
+    pub fn since_start(&self) -> Duration {
+        self.last.duration_since(self.start)
+    }
+
+    /// The random values ran out, e.g. the finite recorded log is replayed.
+    /// The proposals are not delivered since then, the proposer reports the error.
+    pub fn rng_exhausted(&self) -> bool {
+        self.rng_exhausted
+    }
+
+    /// The timers in the returned request are taken, the tracker keeps them.
+    /// If the random values ran out, the proposal is dropped and the request is empty.
     pub fn send(&mut self, kind: ProposalKind<R, W, S::Ext>) -> Request {
         use std::mem;
 
-        let last = mem::replace(&mut self.last, Instant::now());
+        let rng = match self.rngs.next() {
+            Some(rng) => rng,
+            None => {
+                self.rng_exhausted = true;
+                return Request::default();
+            },
+        };
+        let now = self.clock.now();
+        let last = mem::replace(&mut self.last, now);
         let proposal = Proposal {
-            rng: self.rngs.next().unwrap(),
-            elapsed: last.elapsed(),
+            rng,
+            elapsed: now.duration_since(last),
+            since_start: now.duration_since(self.start),
             kind,
         };
 
-        self.state.accept(proposal)
+        let mut request = self.state.accept(proposal);
+        for (key, after) in request.take_timers() {
+            match after {
+                Some(after) => self.timers.schedule(key, now + after),
+                None => self.timers.cancel(&key),
+            }
+        }
+        request
+    }
+
+    /// Not later than the earliest timer is due, it might be earlier.
+    pub fn next_timer(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+
+    /// Remove the timers due by the clock, the keys in order of deadlines.
+    /// The caller sends `Timer` for each, as `fire_timers` does.
+    pub fn take_due_timers(&mut self) -> Vec<u64> {
+        self.timers.expire(self.clock.now())
+    }
+
+    /// Send `Timer` for each timer due by the clock, in order of deadlines.
+    /// `None` if no timer is due. The timers set meanwhile fire on the next call.
+    pub fn fire_timers(&mut self) -> Option<Request> {
+        let due = self.take_due_timers();
+        if due.is_empty() {
+            return None;
+        }
+        let mut request = Request::default();
+        for key in due {
+            request += self.send(ProposalKind::Timer(key));
+        }
+        Some(request)
+    }
+}
+
+impl<Rngs, S, R, W> TimeTracker<Rngs, S, R, W, ManualClock>
+where
+    Rngs: Iterator<Item = S::Rng>,
+    S: State<R, W>,
+    R: ReadOnce,
+    W: WriteOnce,
+{
+    /// Move the clock and fire the timers due, to drive the state without the proposer.
+    pub fn advance(&mut self, duration: Duration) -> Request {
+        self.clock.advance(duration);
+        self.fire_timers().unwrap_or_default()
     }
 }
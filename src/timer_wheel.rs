@@ -0,0 +1,342 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    mem,
+    time::{Duration, Instant},
+};
+
+const LEVELS: usize = 6;
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+// the farthest deadline, about two years of milliseconds, later ones are clamped
+const MAX_TICKS: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1;
+
+/// The hierarchical timing wheel, the resolution is a millisecond.
+/// Each level has 64 slots, a slot of a level spans the whole next lower level.
+/// Scheduling, cancelling and finding the next deadline do not depend on the number of timers,
+/// each timer moves down at most once per level before it fires.
+/// The time starts at the first instant given to the wheel.
+pub struct TimerWheel<K> {
+    origin: Option<Instant>,
+    // the ticks before this are processed
+    elapsed: u64,
+    levels: [Level<K>; LEVELS],
+    // the tick of each scheduled key, the entries in the slots which
+    // do not match are cancelled or rescheduled
+    deadlines: HashMap<K, u64>,
+}
+
+struct Level<K> {
+    occupied: u64,
+    slots: [Vec<(K, u64)>; SLOTS],
+}
+
+impl<K> Default for Level<K> {
+    fn default() -> Self {
+        Level {
+            occupied: 0,
+            slots: [(); SLOTS].map(|()| Vec::new()),
+        }
+    }
+}
+
+impl<K> TimerWheel<K>
+where
+    K: Copy + Eq + Hash,
+{
+    pub fn new() -> Self {
+        TimerWheel {
+            origin: None,
+            elapsed: 0,
+            levels: Default::default(),
+            deadlines: HashMap::new(),
+        }
+    }
+
+    /// The time starts at the given instant, so the first deadline
+    /// given to the wheel is not the origin.
+    pub fn starting_at(origin: Instant) -> Self {
+        let mut s = TimerWheel::new();
+        s.origin = Some(origin);
+        s
+    }
+
+    // the deadline rounds up and the current time rounds down, the timer never fires early
+    fn tick(&mut self, instant: Instant, round_up: bool) -> u64 {
+        let origin = *self.origin.get_or_insert(instant);
+        let nanos = instant.saturating_duration_since(origin).as_nanos();
+        let ticks = if round_up {
+            nanos.div_ceil(1_000_000)
+        } else {
+            nanos / 1_000_000
+        };
+        ticks.min((self.elapsed + MAX_TICKS) as u128) as u64
+    }
+
+    fn instant(&self, tick: u64) -> Option<Instant> {
+        Some(self.origin? + Duration::from_millis(tick))
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.deadlines.contains_key(key)
+    }
+
+    /// Replaces the previous deadline of the key, if any.
+    pub fn schedule(&mut self, key: K, deadline: Instant) {
+        let tick = self.tick(deadline, true).max(self.elapsed);
+        self.deadlines.insert(key, tick);
+        self.insert(key, tick);
+    }
+
+    pub fn cancel(&mut self, key: &K) {
+        // the entry in the slot is dropped lazily
+        self.deadlines.remove(key);
+    }
+
+    fn insert(&mut self, key: K, tick: u64) {
+        let (level, slot) = self.position(tick);
+        let level = &mut self.levels[level];
+        level.slots[slot].push((key, tick));
+        level.occupied |= 1 << slot;
+    }
+
+    // the level is given by the highest bit where the tick differs from the elapsed
+    fn position(&self, tick: u64) -> (usize, usize) {
+        let masked = (self.elapsed ^ tick) | (SLOTS as u64 - 1);
+        let significant = 63 - masked.leading_zeros();
+        let level = (significant / SLOT_BITS) as usize;
+        let slot = (tick >> (level as u32 * SLOT_BITS)) as usize % SLOTS;
+        (level, slot)
+    }
+
+    // the earliest occupied slot and the tick it starts at
+    fn next_slot(&self) -> Option<(usize, usize, u64)> {
+        self.levels
+            .iter()
+            .enumerate()
+            .find(|(_, level)| level.occupied != 0)
+            .map(|(index, level)| {
+                let shift = index as u32 * SLOT_BITS;
+                let slot_range = 1u64 << shift;
+                let level_range = slot_range << SLOT_BITS;
+                let current = (self.elapsed >> shift) as u32 % SLOTS as u32;
+                let distance = level.occupied.rotate_right(current).trailing_zeros();
+                let slot = (current + distance) as usize % SLOTS;
+                let level_start = self.elapsed & !(level_range - 1);
+                let mut start = level_start + slot as u64 * slot_range;
+                if start + slot_range <= self.elapsed {
+                    // the slot belongs to the next rotation
+                    start += level_range;
+                }
+                (index, slot, start)
+            })
+    }
+
+    /// When to call `expire`, never later than the earliest deadline. It might be earlier,
+    /// if the timers only move to the lower level then, or if the timer is cancelled.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.next_slot()
+            .and_then(|(_, _, start)| self.instant(start.max(self.elapsed)))
+    }
+
+    /// Remove the keys whose deadline is due, in order of their deadlines.
+    pub fn expire(&mut self, now: Instant) -> Vec<K> {
+        let now = self.tick(now, false);
+        let mut due = Vec::new();
+        while let Some((level, slot, start)) = self.next_slot() {
+            if start > now {
+                break;
+            }
+            self.elapsed = self.elapsed.max(start);
+            let level = &mut self.levels[level];
+            level.occupied &= !(1 << slot);
+            let entries = mem::take(&mut level.slots[slot]);
+            for (key, tick) in entries {
+                if self.deadlines.get(&key) != Some(&tick) {
+                    continue;
+                }
+                if tick <= now && tick <= self.elapsed {
+                    self.deadlines.remove(&key);
+                    due.push(key);
+                } else {
+                    // move down to the lower level
+                    self.insert(key, tick);
+                }
+            }
+        }
+        self.elapsed = self.elapsed.max(now);
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{TimerWheel, MAX_TICKS};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn never_early() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::starting_at(origin);
+        wheel.schedule(0, origin + ms(1));
+        assert!(wheel.expire(origin + Duration::from_micros(100)).is_empty());
+        assert!(wheel.expire(origin + Duration::from_micros(999)).is_empty());
+        assert_eq!(wheel.expire(origin + ms(1)), [0]);
+
+        // the deadline between the ticks rounds up
+        wheel.schedule(1, origin + Duration::from_micros(2_100));
+        assert!(wheel
+            .expire(origin + Duration::from_micros(2_900))
+            .is_empty());
+        assert_eq!(wheel.expire(origin + ms(3)), [1]);
+    }
+
+    #[test]
+    fn next_deadline_is_due() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::starting_at(origin);
+        wheel.schedule(0, origin + ms(5_000));
+        // the deadline might be earlier, while the timer moves down the levels
+        let mut expired = Vec::new();
+        while expired.is_empty() {
+            let next = wheel.next_deadline().unwrap();
+            assert!(next <= origin + ms(5_000));
+            expired = wheel.expire(next);
+        }
+        assert_eq!(expired, [0]);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn cascade() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::starting_at(origin);
+        // a timer on every level, and beyond the farthest deadline
+        let deadlines = [
+            3,
+            100,
+            5_000,
+            300_000,
+            20_000_000,
+            1_000_000_000,
+            MAX_TICKS * 2,
+        ];
+        for (key, deadline) in deadlines.iter().enumerate() {
+            wheel.schedule(key, origin + ms(*deadline));
+        }
+        for (key, deadline) in deadlines.iter().enumerate() {
+            let deadline = (*deadline).min(MAX_TICKS);
+            assert!(wheel.expire(origin + ms(deadline - 1)).is_empty());
+            assert_eq!(wheel.expire(origin + ms(deadline)), [key]);
+        }
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn cancel_and_reschedule() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::starting_at(origin);
+        wheel.schedule(0, origin + ms(10));
+        wheel.schedule(1, origin + ms(20));
+        wheel.schedule(2, origin + ms(30));
+        wheel.cancel(&1);
+        assert!(!wheel.contains(&1));
+        // later and then earlier, only the last one counts
+        wheel.schedule(0, origin + ms(40));
+        wheel.schedule(2, origin + ms(5));
+        assert_eq!(wheel.expire(origin + ms(35)), [2]);
+        assert_eq!(wheel.expire(origin + ms(40)), [0]);
+        assert!(wheel.expire(origin + ms(1_000)).is_empty());
+
+        // the key can be scheduled again after it fired
+        wheel.schedule(0, origin + ms(1_500));
+        assert_eq!(wheel.expire(origin + ms(2_000)), [0]);
+    }
+
+    #[test]
+    fn order_of_deadlines() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::starting_at(origin);
+        // pseudo random deadlines, up to a couple of hours
+        let mut x = 1u64;
+        let deadlines = (0..10_000)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (x >> 33) % 10_000_000
+            })
+            .collect::<Vec<_>>();
+        for (key, deadline) in deadlines.iter().enumerate() {
+            wheel.schedule(key, origin + ms(*deadline));
+        }
+
+        let mut expired = Vec::new();
+        let mut now = 0;
+        while now <= 10_000_000 {
+            now += 1_000;
+            for key in wheel.expire(origin + ms(now)) {
+                assert!(deadlines[key] <= now && deadlines[key] > now - 1_000);
+                expired.push(key);
+            }
+        }
+        // in order of the deadlines, the same deadlines in any order
+        let order = expired
+            .iter()
+            .map(|key| deadlines[*key])
+            .collect::<Vec<_>>();
+        assert!(order.windows(2).all(|w| w[0] <= w[1]));
+        expired.sort();
+        assert_eq!(expired, (0..deadlines.len()).collect::<Vec<_>>());
+    }
+
+    // the wheel replaces the scan of every timer for the next deadline,
+    // its cost per timer does not depend on the number of timers
+    #[test]
+    fn faster_than_linear_scan() {
+        const N: u64 = 5_000;
+
+        let start = Instant::now();
+        let deadlines = (0..N)
+            .map(|key| start + ms(key * 7919 % 3_600_000))
+            .collect::<Vec<_>>();
+
+        let timer = Instant::now();
+        let mut wheel = TimerWheel::starting_at(start);
+        for (key, deadline) in deadlines.iter().enumerate() {
+            wheel.schedule(key, *deadline);
+        }
+        let mut expired = 0;
+        while let Some(now) = wheel.next_deadline() {
+            expired += wheel.expire(now).len();
+        }
+        let wheel_cost = timer.elapsed();
+        assert_eq!(expired, deadlines.len());
+
+        let timer = Instant::now();
+        let mut pending = deadlines.clone();
+        let mut expired = 0;
+        while let Some(now) = pending.iter().min().cloned() {
+            let before = pending.len();
+            pending.retain(|deadline| *deadline > now);
+            expired += before - pending.len();
+        }
+        let scan_cost = timer.elapsed();
+        assert_eq!(expired, deadlines.len());
+
+        assert!(
+            wheel_cost < scan_cost,
+            "wheel: {:?}, scan: {:?}",
+            wheel_cost,
+            scan_cost
+        );
+    }
+}
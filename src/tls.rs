@@ -0,0 +1,257 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::{
+    io::{self, Read, Write},
+    sync::Arc,
+};
+use rustls::{ClientConfig, ClientConnection, Connection, ServerConfig, ServerConnection, ServerName};
+
+use super::proposal::{ConnectionInterest, IoResult, ReadOnce, WriteOnce};
+
+/// The tls session of the connection. Create it with the config when the state
+/// receives the `Connection` proposal, and keep it along with the connection.
+/// Wrap the io objects the proposer offers with `reader` and `writer`,
+/// the state reads and writes the plaintext through them.
+pub struct Tls {
+    inner: Connection,
+}
+
+impl Tls {
+    /// The session of the incoming connection.
+    pub fn server(config: Arc<ServerConfig>) -> Result<Self, rustls::Error> {
+        Ok(Tls {
+            inner: ServerConnection::new(config)?.into(),
+        })
+    }
+
+    /// The session of the outgoing connection, the name is checked against the certificate.
+    pub fn client(config: Arc<ClientConfig>, name: ServerName) -> Result<Self, rustls::Error> {
+        Ok(Tls {
+            inner: ClientConnection::new(config, name)?.into(),
+        })
+    }
+
+    /// The underlying session, e.g. to read the peer certificates or the negotiated protocol.
+    pub fn connection(&self) -> &Connection {
+        &self.inner
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        self.inner.is_handshaking()
+    }
+
+    /// Which io the session needs to make progress. The handshake needs both reads
+    /// and writes, so keep offering the writer while it is `writable`, even with no data.
+    pub fn interest(&self) -> ConnectionInterest {
+        ConnectionInterest {
+            readable: self.inner.wants_read(),
+            writable: self.inner.wants_write(),
+        }
+    }
+
+    /// Queue the alert that closes the session, the next write sends it.
+    pub fn send_close_notify(&mut self) {
+        self.inner.send_close_notify();
+    }
+
+    pub fn reader<R>(&mut self, once: R) -> TlsReadOnce<'_, R>
+    where
+        R: ReadOnce,
+    {
+        TlsReadOnce {
+            inner: once,
+            tls: self,
+        }
+    }
+
+    pub fn writer<W>(&mut self, once: W) -> TlsWriteOnce<'_, W>
+    where
+        W: WriteOnce,
+    {
+        TlsWriteOnce {
+            inner: once,
+            tls: self,
+        }
+    }
+}
+
+fn into_io(result: IoResult) -> io::Result<usize> {
+    match result {
+        IoResult::Done { length, .. } => Ok(length),
+        IoResult::Eof => Ok(0),
+        IoResult::WouldBlock => Err(io::ErrorKind::WouldBlock.into()),
+        IoResult::Closed => Err(io::ErrorKind::NotConnected.into()),
+        IoResult::Error(kind) => Err(kind.into()),
+    }
+}
+
+// the session might read or write the socket several times, but the io object is used once
+struct Once<T> {
+    inner: Option<T>,
+    result: Option<IoResult>,
+}
+
+impl<T> Once<T> {
+    fn new(inner: T) -> Self {
+        Once {
+            inner: Some(inner),
+            result: None,
+        }
+    }
+}
+
+impl<R> Read for Once<R>
+where
+    R: ReadOnce,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = self.inner.take().ok_or(io::ErrorKind::WouldBlock)?;
+        // the session might hold more records than the state reads at once
+        let result = inner.read_and_keep(buf);
+        self.result = Some(result);
+        into_io(result)
+    }
+}
+
+impl<W> Write for Once<W>
+where
+    W: WriteOnce,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = self.inner.take().ok_or(io::ErrorKind::WouldBlock)?;
+        let result = inner.write(buf);
+        self.result = Some(result);
+        into_io(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the ciphertext from the connection and returns the plaintext.
+/// `Done` with zero length means the read bytes were the handshake or an incomplete record.
+pub struct TlsReadOnce<'a, R> {
+    inner: R,
+    tls: &'a mut Tls,
+}
+
+impl<'a, R> ReadOnce for TlsReadOnce<'a, R>
+where
+    R: ReadOnce,
+{
+    fn read(self, buf: &mut [u8]) -> IoResult {
+        let TlsReadOnce { inner, tls } = self;
+        let conn = &mut tls.inner;
+
+        let pending = match conn.process_new_packets() {
+            Ok(state) => state.plaintext_bytes_to_read(),
+            Err(error) => {
+                log::warn!("tls error: {}", error);
+                inner.defer();
+                return IoResult::Error(io::ErrorKind::InvalidData);
+            },
+        };
+        let result = if pending >= buf.len() {
+            // enough plaintext is decrypted already, do not read the socket,
+            // but ask to be polled again for the rest
+            let _ = inner.read_and_keep(&mut []);
+            None
+        } else if conn.wants_read() {
+            let mut once = Once::new(inner);
+            let _ = conn.read_tls(&mut once);
+            if let Some(inner) = once.inner {
+                inner.defer();
+            }
+            once.result
+        } else {
+            inner.defer();
+            None
+        };
+
+        if let Err(error) = conn.process_new_packets() {
+            log::warn!("tls error: {}", error);
+            return IoResult::Error(io::ErrorKind::InvalidData);
+        }
+        let will_close = match result {
+            Some(IoResult::Done { will_close, .. }) => will_close,
+            _ => false,
+        };
+        match conn.reader().read(buf) {
+            // the peer sent `close_notify`
+            Ok(0) if !buf.is_empty() => IoResult::Eof,
+            Ok(length) => IoResult::Done { length, will_close },
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => match result {
+                Some(IoResult::Done { .. }) => IoResult::Done {
+                    length: 0,
+                    will_close,
+                },
+                Some(IoResult::Closed) => IoResult::Closed,
+                Some(IoResult::Error(kind)) => IoResult::Error(kind),
+                Some(IoResult::Eof) => IoResult::Error(io::ErrorKind::UnexpectedEof),
+                Some(IoResult::WouldBlock) | None => IoResult::WouldBlock,
+            },
+            // the connection is closed without `close_notify`, the data might be truncated
+            Err(error) => IoResult::Error(error.kind()),
+        }
+    }
+
+    fn defer(self) {
+        self.inner.defer()
+    }
+
+    fn close(self) {
+        self.inner.close()
+    }
+}
+
+/// Encrypts the plaintext and writes the ciphertext to the connection.
+/// The ciphertext the connection does not take now is sent by the next write.
+pub struct TlsWriteOnce<'a, W> {
+    inner: W,
+    tls: &'a mut Tls,
+}
+
+impl<'a, W> WriteOnce for TlsWriteOnce<'a, W>
+where
+    W: WriteOnce,
+{
+    fn write(self, data: &[u8]) -> IoResult {
+        let TlsWriteOnce { inner, tls } = self;
+        let conn = &mut tls.inner;
+
+        let length = conn.writer().write(data).unwrap_or(0);
+        let result = if conn.wants_write() {
+            let mut once = Once::new(inner);
+            let _ = conn.write_tls(&mut once);
+            if let Some(inner) = once.inner {
+                inner.defer();
+            }
+            once.result
+        } else {
+            inner.defer();
+            None
+        };
+
+        match result {
+            Some(IoResult::Closed) => IoResult::Closed,
+            Some(IoResult::Error(kind)) => IoResult::Error(kind),
+            Some(IoResult::Done { will_close, .. }) => IoResult::Done { length, will_close },
+            _ if length == 0 && !data.is_empty() => IoResult::WouldBlock,
+            _ => IoResult::Done {
+                length,
+                will_close: false,
+            },
+        }
+    }
+
+    fn defer(self) {
+        self.inner.defer()
+    }
+
+    /// Closes the socket, call `Tls::send_close_notify` and write before to close cleanly.
+    fn close(self) {
+        self.inner.close()
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2021 Vladislav Melnik
+// SPDX-License-Identifier: MIT
+
+use std::time::{Duration, Instant};
+
+/// Allows `per_sec` events per second on average, and up to `burst` at once.
+/// The `burst` is at least one.
+pub struct TokenBucket {
+    period: Option<Duration>,
+    burst: u32,
+    tokens: u32,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(per_sec: u32, burst: u32, now: Instant) -> Self {
+        let burst = burst.max(1);
+        TokenBucket {
+            period: if per_sec == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(1) / per_sec)
+            },
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    pub fn refill(&mut self, now: Instant) {
+        let period = match self.period {
+            Some(period) => period,
+            None => return,
+        };
+        let elapsed = now.saturating_duration_since(self.last);
+        let n = (elapsed.as_nanos() / period.as_nanos()).min(u32::MAX as u128) as u32;
+        if n == 0 {
+            return;
+        }
+        self.tokens = self.tokens.saturating_add(n).min(self.burst);
+        // keep the remainder, unless the bucket is full
+        if self.tokens == self.burst {
+            self.last = now;
+        } else {
+            self.last += period * n;
+        }
+    }
+
+    pub fn available(&self) -> bool {
+        self.tokens > 0
+    }
+
+    pub fn take(&mut self) {
+        self.tokens = self.tokens.saturating_sub(1);
+    }
+
+    /// When the next token appears, `None` if the bucket never refills.
+    pub fn next_refill(&self) -> Option<Instant> {
+        self.period.map(|period| self.last + period)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::TokenBucket;
+
+    #[test]
+    fn zero_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10, 0, now);
+        assert!(bucket.available());
+        bucket.take();
+        assert!(!bucket.available());
+
+        bucket.refill(now + Duration::from_millis(99));
+        assert!(!bucket.available());
+        bucket.refill(now + Duration::from_millis(100));
+        assert!(bucket.available());
+        // never holds more than one
+        bucket.refill(now + Duration::from_secs(10));
+        bucket.take();
+        assert!(!bucket.available());
+    }
+}